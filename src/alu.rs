@@ -0,0 +1,104 @@
+// 乗算/除算ユニット
+// 結果は一度には出ず、CPUサイクルごとに1bitずつ計算される (乗算 8サイクル、除算 16サイクル)。
+// 途中で読むと計算途中の値が見える。
+pub struct ALU {
+  wrmpya: u8, // 4202h WO - WRMPYA  - 乗算 被乗数 (8bit)                (FFh)
+  wrmpyb: u8, // 4203h WO - WRMPYB  - 乗算 乗数 (8bit)、書き込みで開始
+  // 4204h WO - WRDIVL  - 除算 被除数 (下位8bit)                         (FFh)
+  // 4205h WO - WRDIVH  - 除算 被除数 (上位8bit)                         (FFh)
+  wrdiv: u16,
+  wrdivb: u8, // 4206h WO - WRDIVB  - 除算 除数 (8bit)、書き込みで開始
+
+  // 4214h RO - RDDIVL  - 除算結果 商 (下位8bit)
+  // 4215h RO - RDDIVH  - 除算結果 商 (上位8bit)
+  rddiv: u16,
+  // 4216h RO - RDMPYL  - 乗算結果 積 / 除算結果 余り (下位8bit)
+  // 4217h RO - RDMPYH  - 乗算結果 積 / 除算結果 余り (上位8bit)
+  rdmpy: u16,
+
+  mpyctr: u8,
+  divctr: u8,
+  shift: u32,
+}
+
+impl ALU {
+  pub fn new() -> Self {
+    Self {
+      wrmpya: 0xFF,
+      wrmpyb: 0xFF,
+      wrdiv: 0xFFFF,
+      wrdivb: 0xFF,
+      rddiv: 0,
+      rdmpy: 0,
+      mpyctr: 0,
+      divctr: 0,
+      shift: 0,
+    }
+  }
+
+  fn busy(&self) -> bool {
+    self.mpyctr > 0 || self.divctr > 0
+  }
+
+  pub fn write(&mut self, addr: u16, data: u8) {
+    match addr {
+      0x4202 => self.wrmpya = data,
+      0x4203 => {
+        self.rdmpy = 0;
+        // 計算中の書き込みは無視される
+        if self.busy() {
+          return
+        }
+        self.wrmpyb = data;
+        // 計算が終わると RDDIV には WRMPYB が残る
+        self.rddiv = (self.wrmpyb as u16) << 8 | self.wrmpya as u16;
+        self.mpyctr = 8;
+        self.shift = self.wrmpyb as u32;
+      }
+      0x4204 => self.wrdiv = (self.wrdiv & 0xFF00) | data as u16,
+      0x4205 => self.wrdiv = (self.wrdiv & 0x00FF) | (data as u16) << 8,
+      0x4206 => {
+        self.rdmpy = self.wrdiv;
+        if self.busy() {
+          return
+        }
+        self.wrdivb = data;
+        // 0除算では 商=FFFFh、余り=被除数 になる (以下の計算でそのまま求まる)
+        self.divctr = 16;
+        self.shift = (self.wrdivb as u32) << 16;
+      }
+      _ => panic!("not implemented ALU::write({:04X}, {:02X})", addr, data),
+    }
+  }
+
+  pub fn read(&self, addr: u16) -> u8 {
+    match addr {
+      0x4214 => self.rddiv as u8,
+      0x4215 => (self.rddiv >> 8) as u8,
+      0x4216 => self.rdmpy as u8,
+      0x4217 => (self.rdmpy >> 8) as u8,
+      _ => panic!("not implemented ALU::read({:04X})", addr),
+    }
+  }
+
+  // 1 CPUサイクル分進める
+  pub fn step(&mut self) {
+    if self.mpyctr > 0 {
+      self.mpyctr -= 1;
+      if self.rddiv & 1 != 0 {
+        self.rdmpy = self.rdmpy.wrapping_add(self.shift as u16);
+      }
+      self.rddiv >>= 1;
+      self.shift <<= 1;
+    }
+    if self.divctr > 0 {
+      self.divctr -= 1;
+      self.rddiv <<= 1;
+      self.shift >>= 1;
+      if self.rdmpy as u32 >= self.shift {
+        self.rdmpy = (self.rdmpy as u32 - self.shift) as u16;
+        self.rddiv |= 1;
+      }
+    }
+  }
+}
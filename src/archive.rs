@@ -0,0 +1,94 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use flate2::read::GzDecoder;
+use zip::result::ZipError;
+use zip::ZipArchive;
+
+// 圧縮されたROMイメージ (.zip / .gz) の読み込み
+
+#[derive(Debug)]
+pub enum ArchiveError {
+  Io(io::Error),
+  Zip(ZipError),
+  NoRomEntry,
+  EntryNotFound(String),
+}
+
+impl fmt::Display for ArchiveError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      ArchiveError::Io(e) => write!(f, "{}", e),
+      ArchiveError::Zip(e) => write!(f, "{}", e),
+      ArchiveError::NoRomEntry => write!(f, "no .sfc/.smc entry in archive"),
+      ArchiveError::EntryNotFound(name) => write!(f, "entry not found in archive: {}", name),
+    }
+  }
+}
+
+impl std::error::Error for ArchiveError {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    match self {
+      ArchiveError::Io(e) => Some(e),
+      ArchiveError::Zip(e) => Some(e),
+      _ => None,
+    }
+  }
+}
+
+impl From<io::Error> for ArchiveError {
+  fn from(e: io::Error) -> Self {
+    ArchiveError::Io(e)
+  }
+}
+
+impl From<ZipError> for ArchiveError {
+  fn from(e: ZipError) -> Self {
+    ArchiveError::Zip(e)
+  }
+}
+
+const ROM_EXTENSIONS: [&str; 2] = ["sfc", "smc"];
+
+fn has_extension(path: &Path, extensions: &[&str]) -> bool {
+  path
+    .extension()
+    .and_then(|ext| ext.to_str())
+    .is_some_and(|ext| extensions.iter().any(|e| ext.eq_ignore_ascii_case(e)))
+}
+
+pub fn is_archive(path: &Path) -> bool {
+  has_extension(path, &["zip", "gz"])
+}
+
+// .srm や .ips を探すときの基準になるパス
+// foo.zip => foo.zip, foo.sfc.gz => foo.sfc
+pub fn base_path(path: &Path) -> PathBuf {
+  if has_extension(path, &["gz"]) {
+    path.with_extension("")
+  } else {
+    path.to_path_buf()
+  }
+}
+
+// zip は指定されたエントリ、無ければ最初の .sfc/.smc を読む。
+// gz は中身をそのまま展開する。
+pub fn read(path: &Path, entry: Option<&str>) -> Result<Vec<u8>, ArchiveError> {
+  let mut data = vec![];
+  if has_extension(path, &["gz"]) {
+    GzDecoder::new(File::open(path)?).read_to_end(&mut data)?;
+    return Ok(data)
+  }
+
+  let mut zip = ZipArchive::new(File::open(path)?)?;
+  let index = match entry {
+    Some(name) => zip.index_for_name(name).ok_or_else(|| ArchiveError::EntryNotFound(name.to_string()))?,
+    None => (0..zip.len())
+      .find(|&i| zip.name_for_index(i).is_some_and(|name| has_extension(Path::new(name), &ROM_EXTENSIONS)))
+      .ok_or(ArchiveError::NoRomEntry)?,
+  };
+  zip.by_index(index)?.read_to_end(&mut data)?;
+  Ok(data)
+}
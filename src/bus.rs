@@ -1,6 +1,7 @@
 use core::panic;
+use std::collections::HashSet;
 
-use crate::{cartridge::{self, Cartridge}, ppu::PPU, apu::APU};
+use crate::{cartridge::Cartridge, ppu::PPU, apu::APU, alu::ALU, controller::ControllerPorts};
 
 #[repr(u8)]
 enum MemorySpeed {
@@ -9,7 +10,10 @@ enum MemorySpeed {
   XSlow = 12
 }
 
-fn memory_speed(bank: u8, addr: u16) -> MemorySpeed {
+// memsel: 420Dh MEMSEL
+fn memory_speed(bank: u8, addr: u16, memsel: u8) -> MemorySpeed {
+  // 注2 CPU レジスタ 0x420D のビット 0 がセットされている時、 スピードは Fast になり、セットされていない場合は Slow になる。
+  let rom_speed = if memsel & 0x01 != 0 { MemorySpeed::Fast } else { MemorySpeed::Slow };
   match bank {
     0x00..=0x3F => {
       match addr {
@@ -30,27 +34,52 @@ fn memory_speed(bank: u8, addr: u16) -> MemorySpeed {
         0x4000..=0x41FF => MemorySpeed::XSlow,
         0x4200..=0x5FFF => MemorySpeed::Fast,
         0x6000..=0x7FFF => MemorySpeed::Slow,
-        // 注2 CPU レジスタ 0x420D のビット 0 がセットされている時、 スピードは Fast になり、セットされていない場合は Slow になる。
-        0x8000..=0xFFFF => MemorySpeed::Slow // FIXME 注2
+        0x8000..=0xFFFF => rom_speed // 注2
       }
     }
-    0xC0..=0xFF => MemorySpeed::Slow // FIXME 注2
+    0xC0..=0xFF => rom_speed // 注2
   }
 }
 
 
+// 未接続/未実装アドレスへのアクセスの扱い。どれも読み込みはオープンバス、書き込みは捨てる
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnmappedPolicy {
+  // 何もしない
+  Ignore,
+  // アドレスごとに最初の1回だけ表示する
+  LogOnce,
+  // エミュレーションを止める (unmapped_break に内容が入る)
+  Break,
+}
+
 pub struct Bus {
   wram: Vec<u8>,
   pub ppu: PPU,
   pub apu: APU,
-  cartridge: Cartridge,
+  pub cartridge: Cartridge,
+  alu: ALU,
+  pub controllers: ControllerPorts,
   pub cycles: u32,
+  // DMA/HDMA のアクセスは CPU のサイクルとして数えない
+  dma_running: bool,
+  // 実行中の命令でメモリアクセスに使ったCPUサイクル数
+  access_cycles: u8,
 
   // FIXME とりあえず
   pub memory: Vec<u8>, // size=0xFFFFFF
 
+  // MDR: 最後にデータバスに乗った値 (オープンバス)
+  mdr: u8,
+
+  pub unmapped_policy: UnmappedPolicy,
+  unmapped_logged: HashSet<(bool, u8, u16)>,
+  pub unmapped_break: Option<String>,
+
   // 4213h RO - RDIO    - Joypad Programmable I/O Port (Input)
   rdio: u8,
+  // 420Dh WO - MEMSEL  - WS2制御レジスタ (bit0: 80-BF:8000-FFFF, C0-FF:0000-FFFF を FastROM にする)
+  memsel: u8,
 
   // DMA / HDMA
   mdmean: u8, // 420Bh WO - MDMAEN  - GDMAチャネルレジスタ
@@ -70,6 +99,9 @@ pub struct Bus {
   a2: [u16; 8],
   // 43xAh RW - NTRLx   - HDMA Line-Counter (from current Table entry)
   ntrl: [u8; 8],
+  // 43xBh RW - UNUSEDx - 未使用 (読み書きはできる)
+  // 43xFh RW - MIRRx   - 43xBhのミラー
+  unused: [u8; 8],
 
   // wram
   // 2181h WO - WMADDL  - WRAMアドレスレジスタ (下位8bit)  (W)
@@ -77,11 +109,25 @@ pub struct Bus {
   // 2183h WO - WMADDH  - WRAMアドレスレジスタ (上位1bit)  (W)
   wmadd: u32,
 
-  transfered_hdma: bool,
-  transfer: [bool; 8],
-  hdma_transfer_finished: [bool; 8],
+  // HDMA
+  hdma_initialized: bool, // このフレームの初期化が済んだ
+  hdma_line: Option<u16>, // 最後に転送したライン
+  hdma_do_transfer: [bool; 8],
+  hdma_completed: [bool; 8],
 }
 
+// 転送モードごとの B バスアドレス (p = BBADx) のオフセット
+const DMA_PATTERNS: [&[u8]; 8] = [
+  &[0],          // 0b000 1レジスタ1書き込み             1 バイト: p
+  &[0, 1],       // 0b001 2レジスタ1書き込み             2 バイト: p, p+1
+  &[0, 0],       // 0b010 1レジスタ2書き込み             2 バイト: p, p
+  &[0, 0, 1, 1], // 0b011 2レジスタ2書き込み(それぞれ)   4 バイト: p, p, p+1, p+1
+  &[0, 1, 2, 3], // 0b100 4レジスタ1書き込み             4 バイト: p, p+1, p+2, p+3
+  &[0, 1, 0, 1], // 0b101 2レジスタ2書き込み(交互)       4 バイト: p, p+1, p, p+1
+  &[0, 0],       // 0b110 1レジスタ2書き込み             2 バイト: p, p (0b010 と同じ)
+  &[0, 0, 1, 1], // 0b111 2レジスタ2書き込み(それぞれ)   4 バイト: p, p, p+1, p+1 (0b011 と同じ)
+];
+
 #[derive(Debug, Clone, PartialEq)]
 #[allow(non_camel_case_types)]
 enum DMADrection {
@@ -96,10 +142,19 @@ impl Bus {
       ppu,
       apu: APU::new(),
       cartridge,
+      alu: ALU::new(),
+      controllers: ControllerPorts::new(),
       cycles: 0,
+      dma_running: false,
+      access_cycles: 0,
       memory: vec![0; 0x100_0000],
 
+      mdr: 0x00,
+      unmapped_policy: UnmappedPolicy::LogOnce,
+      unmapped_logged: HashSet::new(),
+      unmapped_break: None,
       rdio: 0x00,
+      memsel: 0x00,
 
       mdmean: 0x00,
       hdmean: 0x00,
@@ -109,22 +164,64 @@ impl Bus {
       das: [0xFFFFFF; 8],
       a2: [0xFFFF; 8],
       ntrl: [0xFF; 8],
+      unused: [0xFF; 8],
 
       wmadd: 0x000000,
 
-      transfered_hdma: false,
-      transfer: [false; 8],
-      hdma_transfer_finished: [false; 8],
+      hdma_initialized: false,
+      hdma_line: None,
+      hdma_do_transfer: [false; 8],
+      hdma_completed: [false; 8],
+    }
+  }
+
+  // op_cycles: 実行した命令のCPUサイクル数
+  pub fn tick(&mut self, op_cycles: u8) {
+    // メモリアクセス以外の内部サイクル分も乗除算を進める
+    for _ in self.access_cycles..op_cycles {
+      self.alu.step();
     }
+    self.access_cycles = 0;
+    self.sync_ppu();
+    self.hdma_step();
   }
 
-  pub fn tick(&mut self) {
+  // たまったサイクル分 PPU を進める
+  fn sync_ppu(&mut self) {
     self.ppu.tick(self.cycles);
-    self.hdma_transfer();
     self.cycles = 0;
+    if self.ppu.auto_joypad_request {
+      self.ppu.auto_joypad_request = false;
+      self.controllers.auto_read();
+    }
+  }
+
+  // DMA/HDMA 中は CPU が止まり、その間も PPU は進む
+  fn dma_cycles(&mut self, cycles: u32) {
+    self.cycles += cycles;
+    self.sync_ppu();
   }
 
-  fn write_dma_registers(&mut self, addr: u16, data: u8) {
+  // DMA/HDMA の 1 バイト転送 (8 マスターサイクル)
+  fn dma_byte(&mut self, from: u32, to: u32) {
+    self.dma_running = true;
+    let v = self.mem_read(from);
+    self.mem_write(to, v);
+    self.dma_running = false;
+    self.dma_cycles(8);
+  }
+
+  // DMA/HDMA がテーブルを読む (8 マスターサイクル)
+  fn dma_read(&mut self, addr: u32) -> u8 {
+    self.dma_running = true;
+    let v = self.mem_read(addr);
+    self.dma_running = false;
+    self.dma_cycles(8);
+    v
+  }
+
+  // 43xCh-43xEh は何もつながっていないので None
+  fn write_dma_registers(&mut self, addr: u16, data: u8) -> Option<()> {
     match addr {
       0x420B => {
         // DMA有効
@@ -157,222 +254,252 @@ impl Bus {
           0x08 => self.a2[channel] = (self.a2[channel] & 0xFF00) | (data as u16),
           0x09 => self.a2[channel] = (self.a2[channel] & 0x00FF) | (data as u16) << 8,
           0x0A => self.ntrl[channel] = data,
-          _ => panic!("not implemented write_dma_registers({:04X}, {:02X})", addr, data)
+          0x0B | 0x0F => self.unused[channel] = data,
+          _ => return None,
         }
       }
     }
+    Some(())
+  }
+
+  // 43x0h-43xBh, 43xFh は転送中に進んだ値がそのまま読める
+  fn read_dma_registers(&self, addr: u16) -> Option<u8> {
+    let register = addr & 0x000F;
+    let channel = ((addr & 0x00F0) >> 4) as usize;
+    let value = match register {
+      0x00 => self.dmap[channel],
+      0x01 => self.bbad[channel],
+      0x02 => self.a1[channel] as u8,
+      0x03 => (self.a1[channel] >> 8) as u8,
+      0x04 => (self.a1[channel] >> 16) as u8,
+      0x05 => self.das[channel] as u8,
+      0x06 => (self.das[channel] >> 8) as u8,
+      0x07 => (self.das[channel] >> 16) as u8,
+      0x08 => self.a2[channel] as u8,
+      0x09 => (self.a2[channel] >> 8) as u8,
+      0x0A => self.ntrl[channel],
+      0x0B | 0x0F => self.unused[channel],
+      _ => return None,
+    };
+    Some(value)
   }
 
   fn dma_transfer(&mut self) {
+    if self.mdmean == 0 {
+      return
+    }
+    // 420Bh への書き込みまでを PPU に反映してから、開始のオーバーヘッド
+    self.sync_ppu();
+    self.dma_cycles(8);
     for channel in 0..=7 {
       if self.mdmean & (0x01 << channel) == 0 {
         continue;
       }
       let channel = channel as usize;
 
-      let mut memory_addr = self.a1[channel] as i64;
-      let ppu_addr = 0x002100 | (self.bbad[channel] as u32);
-      let transfer_size = self.das[channel] & 0x00FFFF;
-      let transfer_size = if transfer_size == 0 { 0x10000 } else { transfer_size };
-
-      let increment_direction: i64 = if (self.dmap[channel] & 0x10) == 0 { 1 } else { -1 };
-      let increment_weight: i64 = if (self.dmap[channel] & 0x08) == 0 { increment_direction } else { 0 };
+      // bit4: 0=インクリメント 1=デクリメント、bit3: 1=アドレス固定
+      let increment: u32 = if (self.dmap[channel] & 0x08) != 0 {
+        0
+      } else if (self.dmap[channel] & 0x10) == 0 {
+        1
+      } else {
+        0xFFFF
+      };
       let direction = if (self.dmap[channel] & 0x80) == 0 {
         DMADrection::CPU_TO_PPU
       } else {
         DMADrection::PPU_TO_CPU
       };
-      let mode = self.dmap[channel] & 0x07;
-
-      match mode {
-        0b000 => {
-          // 1レジスタ1書き込み	1 バイト: p
-          for _ in 0..transfer_size {
-            if direction == DMADrection::CPU_TO_PPU {
-              let v = self.mem_read(memory_addr as u32);
-              self.mem_write(ppu_addr, v);
-              memory_addr = (memory_addr & 0xFF0000) | ((memory_addr + (1 * increment_weight)) & 0x00FFFF);
-            } else {
-              panic!("not inplement DMA 1レジスタ1書き込み PPU to CPU")
-            }
-          }
+      let pattern = DMA_PATTERNS[(self.dmap[channel] & 0x07) as usize];
+      // チャネルごとのオーバーヘッド
+      self.dma_cycles(8);
+
+      // DAS が 0 になるまで転送する (0 からなら 0x10000 バイト)
+      let mut i = 0;
+      loop {
+        let memory_addr = self.a1[channel];
+        let ppu_addr = 0x002100 | (self.bbad[channel].wrapping_add(pattern[i % pattern.len()]) as u32);
+        if direction == DMADrection::CPU_TO_PPU {
+          self.dma_byte(memory_addr, ppu_addr);
+        } else {
+          self.dma_byte(ppu_addr, memory_addr);
         }
-        0b001	=> {
-          // 2レジスタ1書き込み
-          for i in 0..transfer_size {
-            if direction == DMADrection::CPU_TO_PPU {
-              let v = self.mem_read(memory_addr as u32);
-              self.mem_write(if (i % 2) == 0 { ppu_addr } else { ppu_addr + 1 }, v);
-              memory_addr = (memory_addr & 0xFF0000) | ((memory_addr + (1 * increment_weight)) & 0x00FFFF);
-            } else {
-              panic!("not inplement DMA 2レジスタ1書き込み PPU to CPU")
-            }
-          }
+        // A1T はバンクをまたがない
+        self.a1[channel] = (memory_addr & 0xFF0000) | (memory_addr.wrapping_add(increment) & 0x00FFFF);
+        self.das[channel] = (self.das[channel] & 0xFF0000) | (self.das[channel].wrapping_sub(1) & 0x00FFFF);
+        i += 1;
+        if self.das[channel] & 0x00FFFF == 0 {
+          break;
         }
-        0b010 => {
-          // panic!("DMA MODE2 {:02X} {:04X}", self.dmap[channel], transfer_size);
-          // 0b010	1レジスタ2書き込み	2 バイト: p, p
-          for i in 0..transfer_size {
-            if direction == DMADrection::CPU_TO_PPU {
-              println!("DMA({}) {:06X} {:04X}", i, memory_addr, ppu_addr);
-              let v = self.mem_read(memory_addr as u32);
-              self.mem_write(ppu_addr, v);
-              // self.mem_write(ppu_addr, v); ??
-              memory_addr = (memory_addr & 0xFF0000) | ((memory_addr + (1 * increment_weight)) & 0x00FFFF);
-            } else {
-              panic!("not inplement DMA 2レジスタ1書き込み PPU to CPU")
-            }
-          }
+        // GP-DMA 中でも HDMA が割り込む。同じチャネルが HDMA に使われたら GP-DMA はそこで終わる
+        if self.hdma_step() && self.hdma_active(channel) {
+          break;
         }
-
-        // 0b011	2レジスタ2書き込み(それぞれ)	4 バイト: p, p, p+1, p+1
-        // 0b100	4レジスタ1書き込み	4 バイト: p, p+1, p+2, p+3
-        // 0b101	2レジスタ2書き込み(交互)	4 バイト: p, p+1, p, p+1
-        // 0b110	1レジスタ2書き込み	2 バイト: p, p
-        // 0b111	2レジスタ2書き込み(それぞれ)	4 バイト: p, p, p+1, p+1
-        _ => panic!("not inplement DMA mode ({:03b})", mode)
       }
     }
+    self.mdmean = 0x00;
+    // 終了後、CPU のクロックに同期し直す
+    self.dma_cycles(8);
   }
 
-  fn hdma_transfer(&mut self) {
-    if self.hdmean == 0 {
-      return
-    }
-    // Vブランク中はHDMAしない
-    if self.ppu.vblank_flag {
-      self.hdma_transfer_finished = [false; 8];
-      return
+  // HDMA の実行タイミングを確認する
+  // フレーム先頭 (V=0, H=6 => 1 ドット) で初期化し、表示ライン (V=0 ~ V-Blank直前) の H=1104 (276 ドット) ごとに転送する
+  // HDMA を実行したら true
+  fn hdma_step(&mut self) -> bool {
+    if self.ppu.v_counter != 0 {
+      self.hdma_initialized = false;
+    } else if !self.hdma_initialized && self.ppu.h_counter >= 1 {
+      self.hdma_initialized = true;
+      self.hdma_line = None;
+      self.hdma_init();
     }
-    if !self.ppu.hblank_flag {
-      self.transfered_hdma = false;
-      return
-    }
-    if self.transfered_hdma {
-      return
+
+    let line = self.ppu.v_counter;
+    if line < self.ppu.vblank_start_line() && self.ppu.h_counter >= 1104 / 4 && self.hdma_line != Some(line) {
+      self.hdma_line = Some(line);
+      self.hdma_run();
+      return true
     }
-    self.transfered_hdma = true;
+    false
+  }
 
-    for channel in 0..=7 {
+  fn hdma_init(&mut self) {
+    if self.hdmean != 0 {
+      self.dma_cycles(18);
+    }
+    // hdma_reload は後ろのチャネルの状態を見るので、先に全チャネルをリセットしておく
+    self.hdma_do_transfer = [false; 8];
+    self.hdma_completed = [false; 8];
+    for channel in 0..8 {
       if self.hdmean & (0x01 << channel) == 0 {
         continue;
       }
+      // 「アドレス」値に、Aアドレスがコピーされる
+      self.a2[channel] = (self.a1[channel] & 0x00FFFF) as u16;
+      self.ntrl[channel] = 0x00;
+      self.hdma_reload(channel);
+    }
+  }
 
-      if self.hdma_transfer_finished[channel] {
+  fn hdma_run(&mut self) {
+    if (0..8).any(|c| self.hdma_active(c)) {
+      self.dma_cycles(18);
+    }
+    for channel in 0..8 {
+      if !self.hdma_active(channel) || !self.hdma_do_transfer[channel] {
         continue;
       }
-
-      let channel = channel as usize;
-      let addressing_mode = self.dmap[channel] & 0x40 == 0; // true=直接
-
-      if self.ppu.v_counter == 0 {
-        // 「アドレス」値に、Aアドレスがコピーされる
-        // テーブルから 0x43xA に対して値をロードする (0x00 をロードすると、その場でチャネルを停止する…だろう)
-        // 必要なら、間接アドレスをロードする => addressing_mode
-        // 転送実行フラグ(DoTransfer) をTrueにする
-        self.a2[channel] = (self.a1[channel] & 0x00FFFF) as u16;
-        self.ntrl[channel] = self.mem_read((self.a1[channel] & 0xFF0000) | self.a2[channel] as u32);
-        self.a2[channel] = self.a2[channel].wrapping_add(1);
-        if addressing_mode == false {
-          // 間接
-          let addr_l = self.mem_read((self.a1[channel] & 0xFF0000) | self.a2[channel] as u32) as u32;
-          self.a2[channel] = self.a2[channel].wrapping_add(1);
-          let addr_h = self.mem_read((self.a1[channel] & 0xFF0000) | self.a2[channel] as u32) as u32;
-          self.a2[channel] = self.a2[channel].wrapping_add(1);
-          self.das[channel] = (self.das[channel] & 0xFF0000) | (addr_h << 8) | addr_l;
-        }
-        self.transfer[channel] = true;
-      }
-
-      let mode = self.dmap[channel] & 0x07;
+      let indirect = self.dmap[channel] & 0x40 != 0;
       let direction = if (self.dmap[channel] & 0x80) == 0 {
         DMADrection::CPU_TO_PPU
       } else {
         DMADrection::PPU_TO_CPU
       };
-      let ppu_addr = 0x002100 | (self.bbad[channel] as u32);
-
-      if self.transfer[channel] {
-        // アドレス/間接アドレスのいずれかから1バイト読み込み、インクリメントする。
-        // -> modeによって、2バイトよむとかも必要。なはず。
-        let value = if addressing_mode {
-          // 直接
-          let v = self.mem_read((self.a1[channel] & 0xFF0000) | self.a2[channel] as u32);
-          self.a2[channel] = self.a2[channel].wrapping_add(1);
-          v
+      // 1ラインで転送するバイト数は転送モードのパターンと同じ
+      for &offset in DMA_PATTERNS[(self.dmap[channel] & 0x07) as usize] {
+        let memory_addr = if indirect {
+          // 間接: バンクは DASBx
+          let addr = self.das[channel];
+          self.das[channel] = (addr & 0xFF0000) | (addr.wrapping_add(1) & 0x00FFFF);
+          addr
         } else {
-          // 間接
-          let addr_l = self.mem_read(self.das[channel]) as u32;
-          self.das[channel] = self.das[channel].wrapping_add(1);
-          let addr_h = self.mem_read(self.das[channel]) as u32;
-          self.das[channel] = self.das[channel].wrapping_add(1);
-          let v = self.mem_read((addr_h << 8) | addr_l);
-          v
+          let addr = (self.a1[channel] & 0xFF0000) | self.a2[channel] as u32;
+          self.a2[channel] = self.a2[channel].wrapping_add(1);
+          addr
         };
-
-        // ポートに1バイト書き込む。Port+1, Port+2, Port+3 も、転送モードによっては書き込む。
-        // 書き込みもモードのよって、2バイトとかもある。
-        match mode {
-          0b000	=> {
-            // 1レジスタ1書き込み	1 バイト: p
-            if direction == DMADrection::CPU_TO_PPU {
-              self.mem_write(ppu_addr, value);
-            } else {
-              panic!("not inplement HDMA 1レジスタ1書き込み PPU to CPU")
-            }
-          }
-          // 0b001	=> {
-          // }
-          0b010 => {
-            // 1レジスタ2書き込み	2 バイト: p, p
-            if direction == DMADrection::CPU_TO_PPU {
-              self.mem_write(ppu_addr, value);
-              self.mem_write(ppu_addr + 1, value);
-            } else {
-              panic!("not inplement HDMA 1レジスタ2書き込み PPU to CPU")
-            }
-          }
-
-          // 0b011	2レジスタ2書き込み(それぞれ)	4 バイト: p, p, p+1, p+1
-          // 0b100	4レジスタ1書き込み	4 バイト: p, p+1, p+2, p+3
-          // 0b101	2レジスタ2書き込み(交互)	4 バイト: p, p+1, p, p+1
-          // 0b110	1レジスタ2書き込み	2 バイト: p, p
-          // 0b111	2レジスタ2書き込み(それぞれ)	4 バイト: p, p, p+1, p+1
-          _ => panic!("not inplement HDMA mode ({:03b})", mode)
+        let ppu_addr = 0x002100 | (self.bbad[channel].wrapping_add(offset) as u32);
+        if direction == DMADrection::CPU_TO_PPU {
+          self.dma_byte(memory_addr, ppu_addr);
+        } else {
+          self.dma_byte(ppu_addr, memory_addr);
         }
       }
+    }
 
-      // 3. 0x43xA をデクリメント
+    for channel in 0..8 {
+      if !self.hdma_active(channel) {
+        continue;
+      }
+      // チャネルごとのオーバーヘッド
+      self.dma_cycles(8);
+      // 0x43xA をデクリメント
       // 転送実行フラグ(DoTransfer) に「繰り返し」ビットと同じ値をセット
       self.ntrl[channel] = self.ntrl[channel].wrapping_sub(1);
-      self.transfer[channel] = self.ntrl[channel] & 0x7F != 0;
-
-      let line_counter = self.ntrl[channel] & 0x7F;
-      if line_counter == 0 {
-        // 「アドレス」から次の1バイトを読み込み、0x43xA に入れる(行番号と繰り返しも同じようにする)。
-        // 間接アドレスモードの時、「アドレス」から2バイト読み込み、「間接アドレス」に入れ、 「アドレス」の値を2バイト分インクリメントする。
-        // 注(奇妙な動作)：0x43xA が 0 で、処理中のチャネルが現在の行で最後のHDMAチャネルだった場合、 「アドレス」から1バイトのみ読み込まれ、下位バイトには0x00が適用される。 「アドレス」は1つだけインクリメントされ、1少ないCPUサイクルが消費される。
-        // 0x43xA が0の時、処理中のHDMAチャネルのこのフレームでの転送は終了する。 0x420c のビットはクリアされないが、次のフレームには自動的に開始される。
-        // 転送実行フラグ(DoTransfer) をTrueにする
-        self.ntrl[channel] = self.mem_read((self.a1[channel] & 0xFF0000) | self.a2[channel] as u32);
+      self.hdma_do_transfer[channel] = self.ntrl[channel] & 0x80 != 0;
+      self.hdma_reload(channel);
+    }
+  }
+
+  // 0x43xA の下位7bitが 0 になったら、テーブルから次のエントリを読み込む
+  fn hdma_reload(&mut self, channel: usize) {
+    if self.ntrl[channel] & 0x7F != 0 {
+      return
+    }
+    let bank = self.a1[channel] & 0xFF0000;
+    self.ntrl[channel] = self.dma_read(bank | self.a2[channel] as u32);
+    self.a2[channel] = self.a2[channel].wrapping_add(1);
+    // 0x43xA が0の時、処理中のHDMAチャネルのこのフレームでの転送は終了する。
+    // 0x420c のビットはクリアされないが、次のフレームには自動的に開始される。
+    self.hdma_completed[channel] = self.ntrl[channel] == 0;
+    self.hdma_do_transfer[channel] = !self.hdma_completed[channel];
+
+    if self.dmap[channel] & 0x40 != 0 {
+      // 間接アドレスモードの時、「アドレス」から2バイト読み込み、「間接アドレス」に入れる。
+      // 注(奇妙な動作)：0x43xA が 0 で、処理中のチャネルが現在の行で最後のHDMAチャネルだった場合、
+      // 「アドレス」から1バイトのみ読み込まれ、下位バイトには0x00が適用される。
+      let last = ((channel + 1)..8).all(|c| !self.hdma_active(c));
+      let addr_l = if self.hdma_completed[channel] && last {
+        0x00
+      } else {
+        let v = self.dma_read(bank | self.a2[channel] as u32) as u32;
         self.a2[channel] = self.a2[channel].wrapping_add(1);
-        if addressing_mode == false {
-          // 間接
-          let addr_l = self.mem_read((self.a1[channel] & 0xFF0000) | self.a2[channel] as u32) as u32;
-          self.a2[channel] = self.a2[channel].wrapping_add(1);
-          let addr_h = self.mem_read((self.a1[channel] & 0xFF0000) | self.a2[channel] as u32) as u32;
-          self.a2[channel] = self.a2[channel].wrapping_add(1);
-          self.das[channel] = (self.das[channel] & 0xFF0000) | (addr_h << 8) | addr_l;
+        v
+      };
+      let addr_h = self.dma_read(bank | self.a2[channel] as u32) as u32;
+      self.a2[channel] = self.a2[channel].wrapping_add(1);
+      self.das[channel] = (self.das[channel] & 0xFF0000) | (addr_h << 8) | addr_l;
+    }
+  }
+
+  fn hdma_active(&self, channel: usize) -> bool {
+    self.hdmean & (0x01 << channel) != 0 && !self.hdma_completed[channel]
+  }
+
+  fn unmapped(&mut self, write: bool, bank: u8, addr: u16) {
+    let access = || format!("unmapped {} {:02X}:{:04X}", if write { "write" } else { "read" }, bank, addr);
+    match self.unmapped_policy {
+      UnmappedPolicy::Ignore => {}
+      UnmappedPolicy::LogOnce => {
+        if self.unmapped_logged.insert((write, bank, addr)) {
+          println!("{}", access());
         }
-        if self.ntrl[channel] == 0 {
-          // 転送終了
-          self.hdma_transfer_finished[channel] = true;
+      }
+      UnmappedPolicy::Break => {
+        if self.unmapped_break.is_none() {
+          self.unmapped_break = Some(access());
         }
-        self.transfer[channel] = true;
       }
     }
   }
 
+  fn unmapped_read(&mut self, bank: u8, addr: u16) -> u8 {
+    self.unmapped(false, bank, addr);
+    self.mdr
+  }
+
+  fn cartridge_read(&mut self, bank: u8, addr: u16) -> u8 {
+    match self.cartridge.read(bank, addr) {
+      Some(v) => v,
+      None => self.unmapped_read(bank, addr),
+    }
+  }
+
+  // メモリアクセス1回を1 CPUサイクルとして数える
+  fn cpu_cycle(&mut self) {
+    self.access_cycles = self.access_cycles.saturating_add(1);
+    self.alu.step();
+  }
+
   fn write_wram_registers(&mut self, addr: u16, data: u8) {
     match addr {
       0x2180 => self.write_wram(data),
@@ -386,9 +513,8 @@ impl Bus {
   fn read_wram_registers(&mut self, addr: u16) -> u8 {
     match addr {
       0x2180 => self.read_wram(),
-      0x2181 => 0, // TODO open bus
-      0x2182 => 0, // TODO open bus
-      0x2183 => 0, // TODO open bus
+      // 書き込み専用なのでオープンバス
+      0x2181..=0x2183 => self.mdr,
       _ => panic!("not implemented read_wram_registers({:04X})", addr),
     }
   }
@@ -419,95 +545,103 @@ impl Mem for Bus {
     let bank = ((addr & 0xFF_0000) >> 16) as u8;
     let addr = (addr & 0x00_FFFF) as u16;
 
-    self.cycles += memory_speed(bank, addr) as u32;
+    if !self.dma_running {
+      self.cycles += memory_speed(bank, addr, self.memsel) as u32;
+      self.cpu_cycle();
+    }
 
-    match bank {
-      0x00..=0x3F => {
+    let value = match bank {
+      // 80-BF は 00-3F のミラー (8000-FFFF は FastROM)
+      0x00..=0x3F | 0x80..=0xBF => {
         match addr {
           0x0000..=0x1FFF => self.wram[addr as usize],
-          0x2100..=0x213F => self.ppu.read(addr),
+          0x2100..=0x213F => match self.ppu.read(addr, self.mdr) {
+            Some(v) => v,
+            None => self.unmapped_read(bank, addr),
+          },
           0x2140..=0x217F => self.apu.read(addr),
           0x2180..=0x2183 => self.read_wram_registers(addr),
-          0x4210..=0x4212 => self.ppu.read(addr),
+          0x4210..=0x4212 => self.ppu.read(addr, self.mdr).unwrap_or(self.mdr),
           0x4213 => self.rdio,
-          0x4214..=0x421F => {
-            println!("mem_read({:02X}:{:04X})", bank, addr);
-            0
-          }
-          0x454C | 0x5241 | 0x5242 => 0, // TODO マリオコレクションでアクセス
-          0x8000..=0xFFFF => self.cartridge.read(bank, addr),
-          _ => panic!("not implemented mem_read({:02X}:{:04X})", bank, addr)
+          // 4016h bit2-7、4017h bit5-7 はオープンバス
+          0x4016 => (self.mdr & 0xFC) | self.controllers.read(addr),
+          0x4017 => (self.mdr & 0xE0) | self.controllers.read(addr),
+          0x4214..=0x4217 => self.alu.read(addr),
+          0x4218..=0x421F => self.controllers.read(addr),
+          0x4300..=0x437F => match self.read_dma_registers(addr) {
+            Some(v) => v,
+            None => self.unmapped_read(bank, addr),
+          },
+          0x6000..=0xFFFF => self.cartridge_read(bank, addr),
+          // 何もつながっていないアドレスはオープンバス
+          _ => self.unmapped_read(bank, addr),
         }
       }
       0x40..=0x7D => {
-        self.cartridge.read(bank, addr)
+        self.cartridge_read(bank, addr)
       }
       0x7E..=0x7F => {
         self.wram[addr as usize]
       }
-      0x80..=0xBF => {
-        match addr {
-          0x0000..=0x1FFF => self.wram[addr as usize],
-          0x2100..=0x213F => self.ppu.read(addr),
-          // 0x4210..=0x4212 => self.ppu.read(addr),
-          0x8000..=0xFFFF => self.cartridge.read(bank, addr),
-          _ => panic!("not implemented mem_read({:02X}:{:04X})", bank, addr)
-        }
-      }
       0xC0..=0xFF => {
-        self.cartridge.read(bank, addr)
+        self.cartridge_read(bank, addr)
       }
-      _ => panic!("not implemented mem_read({:02X}:{:04X})", bank, addr)
-    }
+    };
+    self.mdr = value;
+    value
   }
 
   fn mem_write(&mut self, addr: u32, data: u8) {
     let bank = ((addr & 0xFF_0000) >> 16) as u8;
     let addr = (addr & 0x00_FFFF) as u16;
 
-    self.cycles += memory_speed(bank, addr) as u32;
+    if !self.dma_running {
+      self.cycles += memory_speed(bank, addr, self.memsel) as u32;
+      self.cpu_cycle();
+    }
+
+    self.mdr = data;
 
     match bank {
-    0x00..=0x3F => {
+      // 80-BF は 00-3F のミラー
+      0x00..=0x3F | 0x80..=0xBF => {
         match addr {
           0x0000..=0x1FFF => self.wram[addr as usize] = data,
-          0x2100..=0x213F => self.ppu.write(addr, data),
+          0x2100..=0x213F => {
+            if self.ppu.write(addr, data).is_none() {
+              self.unmapped(true, bank, addr);
+            }
+          }
           0x2140..=0x217F => self.apu.write(addr, data),
           0x2180..=0x2183 => self.write_wram_registers(addr, data),
-          0x2184 => {}, // TODO マリオコレクションでアクセス（これなに？）
-          0x4200..=0x4201 => self.ppu.write(addr, data),
-          0x420B => self.write_dma_registers(addr, data),
-          0x420C => self.write_dma_registers(addr, data),
-          0x4202..=0x420D => {
-            // ~
-            // 420Dh WO - MEMSEL  - WS2制御レジスタ
-            println!("mem_write({:02X}:{:04X}, {:02X})", bank, addr, data)
+          0x4016 => self.controllers.write(addr, data),
+          0x4017 => {}, // 書き込み先なし
+          0x4200..=0x4201 | 0x4207..=0x420A => {
+            self.ppu.write(addr, data);
           }
-          0x4300..=0x437F => {
+          0x420B..=0x420C => {
             self.write_dma_registers(addr, data);
           }
-          // 0x8000..=0xFFFF => self.cartridge.read(bank, addr),
-          _ => panic!("not implemented mem_write({:02X}:{:04X}, {:02X})", bank, addr, data)
+          0x4202..=0x4206 => self.alu.write(addr, data),
+          0x420D => self.memsel = data & 0x01,
+          0x4300..=0x437F => {
+            if self.write_dma_registers(addr, data).is_none() {
+              self.unmapped(true, bank, addr);
+            }
+          }
+          0x6000..=0xFFFF => self.cartridge.write(bank, addr, data),
+          _ => self.unmapped(true, bank, addr),
         }
       }
       0x40..=0x7D => {
-        // self.cartridge.read(bank, addr)
+        self.cartridge.write(bank, addr, data)
       }
       0x7E..=0x7F => {
         self.wram[addr as usize] = data
       }
-      0x80..=0xBF => {
-        match addr {
-          0x0000..=0x1FFF => self.wram[addr as usize] = data,
-          0x2100..=0x213F => self.ppu.write(addr, data),
-          // 0x8000..=0xFFFF => self.cartridge.read(bank, addr),
-          _ => panic!("not implemented mem_write({:02X}:{:04X}, {:02X})", bank, addr, data)
-        }
-      }
       0xC0..=0xFF => {
-        // self.cartridge.read(bank, addr)
+        self.cartridge.write(bank, addr, data)
       }
-      _ => panic!("not implemented mem_write({:02X}:{:04X}, {:02X})", bank, addr, data)
     }
   }
 }
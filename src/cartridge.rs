@@ -1,103 +1,722 @@
+use std::fmt;
 use std::fs::{self, File};
-use std::io::Read;
-use std::io::Write;
-use std::path::PathBuf;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use crate::archive::{self, ArchiveError};
+use crate::patch::{self, PatchError};
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum Mapping {
+  LoRom,   // Mode 20 (LoROM/32K Banks)
+  HiRom,   // Mode 21 (HiROM/64K Banks)
+  ExLoRom, // LoROM 4MB超 (最大8MB)
+  ExHiRom, // Mode 25 (ExHiROM/64K Banks)
+}
+
+// REGION (xxFFD9h)
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum Region {
+  Japan,       // 00h
+  USA,         // 01h
+  Europe,      // 02h
+  Scandinavia, // 03h
+  Finland,     // 04h
+  Denmark,     // 05h
+  France,      // 06h
+  Netherlands, // 07h
+  Spain,       // 08h
+  Germany,     // 09h
+  Italy,       // 0Ah
+  China,       // 0Bh
+  Indonesia,   // 0Ch
+  Korea,       // 0Dh
+  Global,      // 0Eh
+  Canada,      // 0Fh
+  Brazil,      // 10h
+  Australia,   // 11h
+  Unknown(u8),
+}
+
+impl Region {
+  fn from_byte(data: u8) -> Self {
+    match data {
+      0x00 => Region::Japan,
+      0x01 => Region::USA,
+      0x02 => Region::Europe,
+      0x03 => Region::Scandinavia,
+      0x04 => Region::Finland,
+      0x05 => Region::Denmark,
+      0x06 => Region::France,
+      0x07 => Region::Netherlands,
+      0x08 => Region::Spain,
+      0x09 => Region::Germany,
+      0x0A => Region::Italy,
+      0x0B => Region::China,
+      0x0C => Region::Indonesia,
+      0x0D => Region::Korea,
+      0x0E => Region::Global,
+      0x0F => Region::Canada,
+      0x10 => Region::Brazil,
+      0x11 => Region::Australia,
+      n => Region::Unknown(n),
+    }
+  }
+
+  // 02h..0Ch と 11h が PAL。ブラジル(PAL-M)は60Hzなので NTSC 扱い
+  pub fn video_standard(&self) -> VideoStandard {
+    match self {
+      Region::Europe | Region::Scandinavia | Region::Finland | Region::Denmark
+      | Region::France | Region::Netherlands | Region::Spain | Region::Germany
+      | Region::Italy | Region::China | Region::Indonesia | Region::Australia => VideoStandard::Pal,
+      _ => VideoStandard::Ntsc,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum VideoStandard {
+  Ntsc,
+  Pal,
+}
+
+// CHIPSET (xxFFD6h) の上位4bit と、Custom の場合は xxFFBFh のサブタイプ
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum Coprocessor {
+  None,
+  DSP,     // 0
+  SuperFX, // 1 GSU
+  OBC1,    // 2
+  SA1,     // 3
+  SDD1,    // 4
+  SRTC,    // 5
+  Other,   // E Super Game Boy / Satellaview
+  SPC7110, // F,00
+  ST010,   // F,01 ST010/ST011
+  ST018,   // F,02
+  CX4,     // F,10
+  Unknown(u8),
+}
+
+impl Coprocessor {
+  fn from_chipset(chipset: u8, subtype: u8) -> Self {
+    // 下位4bit: 0=ROM, 1=ROM+RAM, 2=ROM+RAM+Battery, 3..6=ROM+Co...
+    if chipset & 0x0F < 0x3 {
+      return Coprocessor::None
+    }
+    match chipset >> 4 {
+      0x0 => Coprocessor::DSP,
+      0x1 => Coprocessor::SuperFX,
+      0x2 => Coprocessor::OBC1,
+      0x3 => Coprocessor::SA1,
+      0x4 => Coprocessor::SDD1,
+      0x5 => Coprocessor::SRTC,
+      0xE => Coprocessor::Other,
+      0xF => match subtype {
+        0x00 => Coprocessor::SPC7110,
+        0x01 => Coprocessor::ST010,
+        0x02 => Coprocessor::ST018,
+        0x10 => Coprocessor::CX4,
+        _ => Coprocessor::Unknown(chipset),
+      },
+      _ => Coprocessor::Unknown(chipset),
+    }
+  }
+
+  // コプロセッサは一つもエミュレートしていないので、載っているカートリッジは正しく動かない
+  pub fn is_supported(&self) -> bool {
+    matches!(self, Coprocessor::None)
+  }
+}
+
+// 内部ヘッダ (xxFFB0h-xxFFDFh) の内容
+#[derive(Debug, Clone, Serialize)]
+pub struct CartridgeInfo {
+  pub title: String,
+  pub maker_code: String,
+  pub game_code: String,
+  pub region: Region,
+  pub version: u8,
+  pub mapping: Mapping,
+  pub rom_size: usize,
+  pub ram_size: usize,
+  pub expansion_ram_size: usize,
+  pub coprocessor: Coprocessor,
+  pub has_battery: bool,
+  pub checksum_valid: bool,
+  pub video_standard: VideoStandard,
+}
+
+impl CartridgeInfo {
+  fn parse(rom: &[u8], header: usize, mapping: Mapping) -> Self {
+    let h = &rom[header..header + 0x20];
+    // 後期型拡張ヘッダ (xxFFB0h-xxFFBFh) は 旧メーカーコード(xxFFDAh) が 33h の時のみ有効
+    let extended = if h[0x1A] == 0x33 { Some(&rom[header - 0x10..header]) } else { None };
+
+    let maker_code = match extended {
+      Some(ext) => ascii_string(&ext[0x00..0x02]),
+      None => format!("{:02X}", h[0x1A]),
+    };
+    let game_code = match extended {
+      Some(ext) => ascii_string(&ext[0x02..0x06]),
+      None => String::new(),
+    };
+    let expansion_ram_size = match extended {
+      Some(ext) => kilobytes(ext[0x0D]),
+      None => 0,
+    };
+    let subtype = extended.map_or(0, |ext| ext[0x0F]);
+
+    let region = Region::from_byte(h[0x19]);
+    let complement = h[0x1C] as u16 | (h[0x1D] as u16) << 8;
+    let checksum = h[0x1E] as u16 | (h[0x1F] as u16) << 8;
+
+    Self {
+      title: title_string(&h[0x00..0x15]),
+      maker_code,
+      game_code,
+      region,
+      version: h[0x1B],
+      mapping,
+      rom_size: kilobytes(h[0x17]),
+      ram_size: kilobytes(h[0x18]),
+      expansion_ram_size,
+      coprocessor: Coprocessor::from_chipset(h[0x16], subtype),
+      has_battery: has_battery(h[0x16]),
+      checksum_valid: checksum ^ complement == 0xFFFF && checksum == compute_checksum(rom),
+      video_standard: region.video_standard(),
+    }
+  }
+}
+
+// 0=なし, n=1<<n KB
+fn kilobytes(n: u8) -> usize {
+  match n {
+    0 => 0,
+    n if n <= 0x10 => 0x400 << n,
+    _ => 0,
+  }
+}
+
+fn ascii_string(data: &[u8]) -> String {
+  data.iter().map(|&c| if (0x20..=0x7E).contains(&c) { c as char } else { '?' }).collect()
+}
+
+// タイトルは ASCII と JIS X 0201 の半角カナ (A1h-DFh)
+fn title_string(data: &[u8]) -> String {
+  let title: String = data
+    .iter()
+    .map(|&c| match c {
+      0x20..=0x7E => c as char,
+      0xA1..=0xDF => char::from_u32(0xFF61 + (c - 0xA1) as u32).unwrap_or('?'),
+      _ => ' ',
+    })
+    .collect();
+  title.trim_end().to_string()
+}
+
+// 全バイトの合計の下位16bit。
+// 2の累乗でないサイズの場合は、余りの部分を繰り返して次の2の累乗まで埋めた値で計算する。
+fn compute_checksum(rom: &[u8]) -> u16 {
+  let sum = |data: &[u8]| data.iter().fold(0u32, |acc, &v| acc.wrapping_add(v as u32));
+  if rom.is_empty() {
+    return 0
+  }
+  let base = if rom.len().is_power_of_two() { rom.len() } else { rom.len().next_power_of_two() / 2 };
+  let mut total = sum(&rom[..base]);
+  let rest = &rom[base..];
+  if !rest.is_empty() {
+    let repeat = (base / rest.len()) as u32;
+    total = total.wrapping_add(sum(rest).wrapping_mul(repeat));
+  }
+  total as u16
+}
+
+#[derive(Debug)]
+pub enum CartridgeError {
+  Io(io::Error),
+  TooSmall(usize),
+  UnrecognizedHeader,
+  UnsupportedCoprocessor(Coprocessor),
+  Patch(PatchError),
+  Archive(ArchiveError),
+}
+
+impl fmt::Display for CartridgeError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      CartridgeError::Io(e) => write!(f, "failed to read ROM: {}", e),
+      CartridgeError::TooSmall(size) => write!(f, "ROM image is too small ({} bytes)", size),
+      CartridgeError::UnrecognizedHeader => write!(f, "no valid internal ROM header found"),
+      CartridgeError::UnsupportedCoprocessor(co) => write!(f, "unsupported coprocessor: {:?}", co),
+      CartridgeError::Patch(e) => write!(f, "failed to apply patch: {}", e),
+      CartridgeError::Archive(e) => write!(f, "failed to extract ROM: {}", e),
+    }
+  }
+}
+
+impl std::error::Error for CartridgeError {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    match self {
+      CartridgeError::Io(e) => Some(e),
+      CartridgeError::Patch(e) => Some(e),
+      CartridgeError::Archive(e) => Some(e),
+      _ => None,
+    }
+  }
+}
+
+impl From<io::Error> for CartridgeError {
+  fn from(e: io::Error) -> Self {
+    CartridgeError::Io(e)
+  }
+}
+
+impl From<PatchError> for CartridgeError {
+  fn from(e: PatchError) -> Self {
+    CartridgeError::Patch(e)
+  }
+}
+
+impl From<ArchiveError> for CartridgeError {
+  fn from(e: ArchiveError) -> Self {
+    CartridgeError::Archive(e)
+  }
+}
+
+const PATCH_EXTENSIONS: [&str; 3] = ["ips", "ups", "bps"];
+
+// LoROM 1バンク分 (ヘッダ xx7FC0h を含む) に満たないものは読み込まない
+const MIN_ROM_SIZE: usize = 0x8000;
+
+// 内部ヘッダの候補位置 (ROMファイル先頭からのオフセット)
+const LOROM_HEADER: usize = 0x007FC0;
+const HIROM_HEADER: usize = 0x00FFC0;
+const EXHIROM_HEADER: usize = 0x40FFC0;
 
 pub struct Cartridge {
   rom: Vec<u8>,
+  sram: Vec<u8>,
+  header: usize,
+  mapping: Mapping,
+  copier_header: bool,
+  info: CartridgeInfo,
+  // バッテリーバックアップされたSRAMの保存先 (<rom>.srm)
+  sram_path: Option<PathBuf>,
+  sram_dirty: bool,
 }
 
 impl Cartridge {
-  pub fn new(filename: &str) -> Self {
-    let mut f = File::open(&filename).expect("no file found");
-    let metadata = fs::metadata(&filename).expect("unable to read metadata");
-    let mut rom = vec![0; metadata.len() as usize];
-    f.read(&mut rom).expect("buffer overflow");
-
-    // title
-    // println!("{:02X?}", &rom[0xFFC0..=0xFFD4]);
-    println!("MAPPING MODE: {:02X}, CHIPSET: {:02X}, ROM: 1<<{}KB, RAM: 1<<{}KB", rom[0xFFD5], rom[0xFFD6], rom[0xFFD7], rom[0xFFD8]);
-    if rom[0xFFDA] == 0x33 {
-      // 後期型拡張ヘッダあり
-      println!("後期型拡張ヘッダ: {:02X?}", &rom[0xFFB0..=0xFFBF]);
-    }
-    // MM: 0x31 = 0b0011_0001
-    // CS: 0x02 = ROM+RAM+Battery
-    Self {
-      rom
+  // 同じ名前の .ips/.ups/.bps があれば自動で当てる
+  pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, CartridgeError> {
+    Self::open_with(path, None, None::<&Path>)
+  }
+
+  // entry: .zip の中から読むファイル名 (無ければ最初の .sfc/.smc)
+  // patch: 当てるパッチ (無ければ同じ名前の .ips/.ups/.bps)
+  pub fn open_with<P: AsRef<Path>, Q: AsRef<Path>>(path: P, entry: Option<&str>, patch: Option<Q>) -> Result<Self, CartridgeError> {
+    let path = path.as_ref();
+    let rom = if archive::is_archive(path) {
+      archive::read(path, entry)?
+    } else {
+      fs::read(path)?
+    };
+    let path = archive::base_path(path);
+    let patch_path = match patch {
+      Some(patch) => Some(patch.as_ref().to_path_buf()),
+      None => PATCH_EXTENSIONS.iter().map(|ext| path.with_extension(ext)).find(|p| p.is_file()),
+    };
+    let patch = match &patch_path {
+      Some(patch_path) => Some(fs::read(patch_path)?),
+      None => None,
+    };
+    let mut cartridge = match &patch {
+      Some(patch) => Self::from_bytes_with_patch(rom, Some(patch))?,
+      None => Self::from_bytes(rom)?,
+    };
+    if cartridge.info.ram_size > 0 && cartridge.info.has_battery {
+      cartridge.attach_sram_file(path.with_extension("srm"));
+    }
+    Ok(cartridge)
+  }
+
+  pub fn from_bytes(rom: Vec<u8>) -> Result<Self, CartridgeError> {
+    Self::from_bytes_with_patch(rom, None)
+  }
+
+  // パッチはコピアーヘッダを取り除いたイメージに当ててから、ヘッダを探す
+  // (ヘッダ付きのダンプ用の IPS はオフセットをずらして当てる)
+  pub fn from_bytes_with_patch(mut rom: Vec<u8>, patch: Option<&[u8]>) -> Result<Self, CartridgeError> {
+    let copier_header = has_copier_header(&rom);
+    if copier_header {
+      rom.drain(..COPIER_HEADER_SIZE);
+    }
+    if let Some(patch) = patch {
+      rom = patch::apply(&rom, patch, if copier_header { COPIER_HEADER_SIZE } else { 0 })?;
+    }
+    if rom.len() < MIN_ROM_SIZE {
+      return Err(CartridgeError::TooSmall(rom.len()))
+    }
+    let (header, mapping) = detect_header(&rom).ok_or(CartridgeError::UnrecognizedHeader)?;
+
+    let info = CartridgeInfo::parse(&rom, header, mapping);
+    if !info.coprocessor.is_supported() {
+      return Err(CartridgeError::UnsupportedCoprocessor(info.coprocessor))
+    }
+
+    Ok(Self {
+      rom,
+      sram: vec![0; info.ram_size],
+      header,
+      mapping,
+      copier_header,
+      info,
+      sram_path: None,
+      sram_dirty: false,
+    })
+  }
+
+  // .srm があれば読み込み、以降の保存先にする
+  fn attach_sram_file(&mut self, path: PathBuf) {
+    if let Ok(data) = fs::read(&path) {
+      let len = data.len().min(self.sram.len());
+      self.sram[..len].copy_from_slice(&data[..len]);
+    }
+    self.sram_path = Some(path);
+  }
+
+  pub fn info(&self) -> &CartridgeInfo {
+    &self.info
+  }
+
+  pub fn mapping(&self) -> Mapping {
+    self.mapping
+  }
+
+  // 内部ヘッダ (xxFFC0h) のROMイメージ上のオフセット
+  pub fn header_offset(&self) -> usize {
+    self.header
+  }
+
+  // 読み込み時にコピアーヘッダを取り除いたか
+  pub fn had_copier_header(&self) -> bool {
+    self.copier_header
+  }
+
+  // 前回の保存以降にSRAMが書き換えられていれば .srm に書き出す
+  pub fn save_sram(&mut self) -> io::Result<()> {
+    if !self.sram_dirty {
+      return Ok(())
+    }
+    if let Some(path) = &self.sram_path {
+      let mut f = File::create(path)?;
+      f.write_all(&self.sram)?;
+    }
+    self.sram_dirty = false;
+    Ok(())
+  }
+
+  fn write_sram(&mut self, offset: usize, data: u8) {
+    if self.sram.is_empty() {
+      return
+    }
+    let offset = offset % self.sram.len();
+    if self.sram[offset] != data {
+      self.sram[offset] = data;
+      self.sram_dirty = true;
     }
   }
 
-  fn mapping_mode(&self) -> u8 {
-    self.rom[0xFFD5] & 0x0F
+  // ROMサイズが2の累乗でない場合もあるので、範囲外はミラーする
+  fn rom_byte(&self, offset: usize) -> u8 {
+    self.rom[offset % self.rom.len()]
+  }
+
+  fn sram_byte(&self, offset: usize) -> Option<u8> {
+    if self.sram.is_empty() {
+      return None
+    }
+    Some(self.sram[offset % self.sram.len()])
   }
 
-  pub fn read(&self, bank: u8, addr: u16) -> u8 {
-    match self.mapping_mode() {
-      0x0 => {
-        // LoROM/32K Banks             Mode 20 (LoROM)
+  fn lorom_read(&self, bank: u8, addr: u16) -> Option<u8> {
+    // 32KB/バンク。40-6F/C0-EF の下半分は上半分のミラー
+    // ExLoROM: 80-FF が先頭 4MB、00-7D がそれ以降
+    let base = if self.mapping == Mapping::ExLoRom && bank & 0x80 == 0 { 0x400000 } else { 0 };
+    let offset = base | (((bank & 0x7F) as usize) << 15) | (addr as usize & 0x7FFF);
+    match bank {
+      0x00..=0x3F | 0x80..=0xBF => {
+        match addr {
+          0x8000..=0xFFFF => Some(self.rom_byte(offset)),
+          _ => None,
+        }
+      }
+      0x40..=0x6F | 0xC0..=0xEF => Some(self.rom_byte(offset)),
+      0x70..=0x7D | 0xF0..=0xFF => {
+        match addr {
+          // 70-7D/F0-FF:0000-7FFF => SRAM (32KB/バンク)
+          0x0000..=0x7FFF => self.sram_byte(lorom_sram_offset(bank, addr)),
+          0x8000..=0xFFFF => Some(self.rom_byte(offset)),
+        }
+      }
+      _ => None,
+    }
+  }
+
+  fn hirom_read(&self, bank: u8, addr: u16) -> Option<u8> {
+    // 64KB/バンク。C0-FF が本体で、40-7D と 00-3F/80-BF の上半分はそのミラー
+    // ExHiROM: C0-FF/80-BF が先頭 4MB、40-7D/00-3F がそれ以降
+    let base = if self.mapping == Mapping::ExHiRom && bank & 0x80 == 0 { 0x400000 } else { 0 };
+    let offset = base | (((bank & 0x3F) as usize) << 16) | addr as usize;
+    match bank {
+      0x40..=0x7D | 0xC0..=0xFF => Some(self.rom_byte(offset)),
+      0x00..=0x3F | 0x80..=0xBF => {
+        match addr {
+          0x8000..=0xFFFF => Some(self.rom_byte(offset)),
+          // 20-3F/A0-BF:6000-7FFF => SRAM (8KB/バンク)
+          0x6000..=0x7FFF if bank & 0x20 != 0 => self.sram_byte(hirom_sram_offset(bank, addr)),
+          _ => None,
+        }
+      }
+      _ => None,
+    }
+  }
+
+  // カートリッジが応答しないアドレス (SRAM が無い場合を含む) は None
+  pub fn read(&self, bank: u8, addr: u16) -> Option<u8> {
+    match self.mapping {
+      Mapping::LoRom | Mapping::ExLoRom => self.lorom_read(bank, addr),
+      Mapping::HiRom | Mapping::ExHiRom => self.hirom_read(bank, addr),
+    }
+  }
+
+  pub fn write(&mut self, bank: u8, addr: u16, data: u8) {
+    match self.mapping {
+      Mapping::LoRom | Mapping::ExLoRom => {
         match bank {
-          0x00..=0x2F => {
-            match addr {
-              0x8000..=0xFFFF => self.rom[addr as usize - 0x8000],
-              _ => panic!("should not reach ROM: {:02X}:{:04X}", bank, addr)
-            }
-          }
-          0x30..=0x3F => {
-            match addr {
-              0x8000..=0xFFFF => self.rom[addr as usize - 0x8000],
-              _ => panic!("should not reach ROM: {:02X}:{:04X}", bank, addr)
-            }
-          }
-          0x40..=0x5F => {
-            match addr {
-              0x0000..=0x7FFF => self.rom[addr as usize],
-              0x8000..=0xFFFF => self.rom[addr as usize - 0x8000],
-            }
+          0x70..=0x7D | 0xF0..=0xFF if addr < 0x8000 => {
+            self.write_sram(lorom_sram_offset(bank, addr), data)
           }
-          0x70..=0x77	=> {
-            match addr {
-              0x0000..=0x7FFF => {
-                0 // FIXME Mode 20 SRAM (256Kバイト)
-              }
-              _ => panic!("should not reach ROM: {:02X}:{:04X}", bank, addr)
-            }
-          }
-          // 0x80~0xDF バンク0x00-0x5Fのミラー
-          0x80..=0xAF => {
-            match addr {
-              0x8000..=0xFFFF => self.rom[addr as usize - 0x8000],
-              _ => panic!("should not reach ROM: {:02X}:{:04X}", bank, addr)
-            }
-          }
-          0xB0..=0xBF => {
-            match addr {
-              0x8000..=0xFFFF => self.rom[addr as usize - 0x8000],
-              _ => panic!("should not reach ROM: {:02X}:{:04X}", bank, addr)
-            }
-          }
-          0xC0..=0xDF => {
-            match addr {
-              0x0000..=0x7FFF => self.rom[addr as usize],
-              0x8000..=0xFFFF => self.rom[addr as usize - 0x8000],
-            }
-          },
-          0xE0..=0xFF => {
-            // 予約済み
-            0
+          _ => {} // ROMへの書き込みは無視
+        }
+      }
+      Mapping::HiRom | Mapping::ExHiRom => {
+        match bank {
+          0x20..=0x3F | 0xA0..=0xBF if (0x6000..=0x7FFF).contains(&addr) => {
+            self.write_sram(hirom_sram_offset(bank, addr), data)
           }
-          _ => panic!("should not reach ROM: {:02X}:{:04X}", bank, addr)
+          _ => {} // ROMへの書き込みは無視
         }
       }
-      0x1 => {
-        // HiROM/64K Banks             Mode 21 (HiROM)
-        0
+    }
+  }
+}
+
+// CHIPSET (xxFFD6h) の下位4bit
+// 2=ROM+RAM+Battery, 5=ROM+Co+RAM+Battery, 6=ROM+Co+Battery
+fn has_battery(chipset: u8) -> bool {
+  matches!(chipset & 0x0F, 0x2 | 0x5 | 0x6)
+}
+
+fn lorom_sram_offset(bank: u8, addr: u16) -> usize {
+  (((bank & 0x0F) as usize) << 15) | addr as usize
+}
+
+fn hirom_sram_offset(bank: u8, addr: u16) -> usize {
+  (((bank & 0x1F) as usize) << 13) | (addr as usize - 0x6000)
+}
+
+// コピアーヘッダ (SMC/SWC等) はファイル先頭の512バイト
+const COPIER_HEADER_SIZE: usize = 0x200;
+
+// ROMサイズが 32KB 単位から 512 バイトずれていれば、コピアーヘッダ付きとみなす
+fn has_copier_header(rom: &[u8]) -> bool {
+  rom.len() % 0x8000 == COPIER_HEADER_SIZE
+}
+
+fn header_mapping(rom: &[u8], header: usize) -> Mapping {
+  match header {
+    LOROM_HEADER if rom.len() > 0x400000 => Mapping::ExLoRom,
+    LOROM_HEADER => Mapping::LoRom,
+    HIROM_HEADER => Mapping::HiRom,
+    _ => Mapping::ExHiRom,
+  }
+}
+
+// MAPPING MODE (xxFFD5h) の下位4bit
+// 0=LoROM/32K Banks             Mode 20 (LoROM)
+// 1=HiROM/64K Banks             Mode 21 (HiROM)
+// 2=LoROM/32K Banks + S-DD1     Mode 22 (mappable) "Super MMC"
+// 3=LoROM/32K Banks + SA-1      Mode 23 (mappable) "Emulates Super MMC"
+// 5=HiROM/64K Banks             Mode 25 (ExHiROM)
+// A=HiROM/64K Banks + SPC7110   Mode 25? (mappable)
+fn mapping_mode_matches(mapping: Mapping, mode: u8) -> bool {
+  match mapping {
+    Mapping::LoRom | Mapping::ExLoRom => matches!(mode & 0x0F, 0x0 | 0x2 | 0x3),
+    Mapping::HiRom => matches!(mode & 0x0F, 0x1 | 0xA),
+    Mapping::ExHiRom => mode & 0x0F == 0x5,
+  }
+}
+
+fn checksum_complement_ok(rom: &[u8], header: usize) -> bool {
+  let complement = rom[header + 0x1C] as u16 | (rom[header + 0x1D] as u16) << 8;
+  let checksum = rom[header + 0x1E] as u16 | (rom[header + 0x1F] as u16) << 8;
+  checksum ^ complement == 0xFFFF
+}
+
+// これより低い点のヘッダは ROM ではないものとして扱う
+const MIN_HEADER_SCORE: i32 = 4;
+
+// 候補位置のヘッダらしさを点数にする (bsnes/snes9x と同じ考え方)
+// - チェックサムと補数の組
+// - リセットベクタ (xxFFFCh) と、その飛び先の最初の命令
+// - MAPPING MODE と位置の一致
+// - タイトルが ASCII か
+fn score_header(rom: &[u8], header: usize) -> Option<i32> {
+  if rom.len() < header + 0x40 {
+    return None
+  }
+  let mapping = header_mapping(rom, header);
+  let mut score = 0;
+
+  let checksum_ok = checksum_complement_ok(rom, header);
+  if checksum_ok {
+    score += 4;
+  }
+
+  let reset_vector = rom[header + 0x3C] as usize | (rom[header + 0x3D] as usize) << 8;
+  if reset_vector < 0x8000 {
+    // 00:0000-7FFF は ROM ではないので、ここから起動することはない
+    score -= 4;
+  } else {
+    // リセットベクタはバンク00から読まれるので、ヘッダと同じ32KB/64KBブロック内にある
+    let opcode_addr = match mapping {
+      Mapping::LoRom | Mapping::ExLoRom => (header & !0x7FFF) | (reset_vector & 0x7FFF),
+      Mapping::HiRom | Mapping::ExHiRom => (header & !0xFFFF) | reset_vector,
+    };
+    score += match rom.get(opcode_addr) {
+      // SEI, CLC, SEP, REP, XCE, JML
+      Some(0x78 | 0x18 | 0xE2 | 0xC2 | 0xFB | 0x5C) => 8,
+      // STZ, LDA, LDX, LDY, JMP, JSR, JSL
+      Some(0x9C | 0xA9 | 0xA2 | 0xA0 | 0x4C | 0x20 | 0x22) => 4,
+      // BRK, COP, STP, WDM, SBC long
+      Some(0x00 | 0x02 | 0xDB | 0x42 | 0xFF) => -8,
+      _ => 0,
+    };
+  }
+
+  let mode_ok = mapping_mode_matches(mapping, rom[header + 0x15]);
+  if mode_ok {
+    score += 2;
+  }
+  // ROMサイズ (xxFFD7h) は 1<<n KB で、8MB(n=13)を超えることはない
+  if rom[header + 0x17] <= 0x0D {
+    score += 1;
+  }
+  // RAMサイズ (xxFFD8h)
+  if rom[header + 0x18] <= 0x08 {
+    score += 1;
+  }
+
+  let title = &rom[header..header + 0x15];
+  if title.iter().all(|c| (0x20..=0x7E).contains(c)) {
+    score += 2;
+  }
+
+  // チェックサムと補数の組が合っているか、MAPPING MODE が合っていてリセットベクタが ROM を指していること
+  if !(checksum_ok || (mode_ok && reset_vector >= 0x8000)) || score < MIN_HEADER_SCORE {
+    return None
+  }
+  Some(score)
+}
+
+// LoROM, HiROM, ExHiROM の候補ヘッダを採点し、一番点の高いものを採用する。
+// 同点の場合は LoROM, HiROM, ExHiROM の順に優先する。どれもヘッダらしくなければ None
+fn detect_header(rom: &[u8]) -> Option<(usize, Mapping)> {
+  let candidates = [LOROM_HEADER, HIROM_HEADER, EXHIROM_HEADER];
+  let mut best: Option<(usize, i32)> = None;
+  for header in candidates {
+    if let Some(score) = score_header(rom, header) {
+      if best.is_none_or(|(_, best_score)| score > best_score) {
+        best = Some((header, score));
       }
-      // 2=LoROM/32K Banks + S-DD1     Mode 22 (mappable) "Super MMC"
-      // 3=LoROM/32K Banks + SA-1      Mode 23 (mappable) "Emulates Super MMC"
-      // 5=HiROM/64K Banks             Mode 25 (ExHiROM)
-      // A=HiROM/64K Banks + SPC7110   Mode 25? (mappable)
-      mode => panic!("invalid mapping mode {:02X}", mode)
     }
   }
+  best.map(|(header, _)| (header, header_mapping(rom, header)))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // 指定した位置に内部ヘッダを持つ ROM イメージ。リセットベクタは 8000h で、飛び先は SEI
+  fn rom_with_header(size: usize, header: usize, map_mode: u8) -> Vec<u8> {
+    let mut rom = vec![0; size];
+    rom[header..header + 0x15].copy_from_slice(b"TEST                 ");
+    rom[header + 0x15] = map_mode;
+    rom[header + 0x17] = 0x08;
+    // 補数 0000h, チェックサム FFFFh
+    rom[header + 0x1E] = 0xFF;
+    rom[header + 0x1F] = 0xFF;
+    rom[header + 0x3D] = 0x80;
+    let opcode_addr = if header == LOROM_HEADER { 0x0000 } else { 0x8000 };
+    rom[opcode_addr] = 0x78;
+    rom
+  }
+
+  #[test]
+  fn detect_lorom_header() {
+    let rom = rom_with_header(0x20000, LOROM_HEADER, 0x20);
+    assert_eq!(detect_header(&rom), Some((LOROM_HEADER, Mapping::LoRom)));
+  }
+
+  #[test]
+  fn detect_hirom_header() {
+    let rom = rom_with_header(0x20000, HIROM_HEADER, 0x21);
+    assert_eq!(detect_header(&rom), Some((HIROM_HEADER, Mapping::HiRom)));
+  }
+
+  #[test]
+  fn detect_exhirom_header() {
+    let mut rom = rom_with_header(0x410000, EXHIROM_HEADER, 0x25);
+    rom[0x408000] = 0x78;
+    assert_eq!(detect_header(&rom), Some((EXHIROM_HEADER, Mapping::ExHiRom)));
+  }
+
+  #[test]
+  fn copier_header_size() {
+    assert!(has_copier_header(&[0; 0x8200]));
+    assert!(!has_copier_header(&[0; 0x8000]));
+    assert!(!has_copier_header(&[0; 0x8100]));
+  }
+
+  #[test]
+  fn strip_copier_header() {
+    let mut rom = vec![0xAA; COPIER_HEADER_SIZE];
+    rom.extend(rom_with_header(0x20000, LOROM_HEADER, 0x20));
+    let cartridge = Cartridge::from_bytes(rom).unwrap();
+    assert!(cartridge.had_copier_header());
+    assert_eq!(cartridge.header_offset(), LOROM_HEADER);
+    assert_eq!(cartridge.mapping(), Mapping::LoRom);
+    assert_eq!(cartridge.info().title, "TEST");
+  }
+
+  #[test]
+  fn reject_zero_filled_image() {
+    assert_eq!(detect_header(&[0; 0x20000]), None);
+    assert!(matches!(Cartridge::from_bytes(vec![0; 0x20000]), Err(CartridgeError::UnrecognizedHeader)));
+  }
+
+  #[test]
+  fn reject_non_rom_data() {
+    // PNG のシグネチャで始まり、残りは同じパターンの繰り返し
+    let mut data: Vec<u8> = (0..0x20000).map(|i| (i * 7 % 251) as u8).collect();
+    data[..8].copy_from_slice(b"\x89PNG\r\n\x1a\n");
+    assert!(matches!(Cartridge::from_bytes(data), Err(CartridgeError::UnrecognizedHeader)));
+  }
 }
@@ -0,0 +1,174 @@
+// コントローラポート
+// 4016h W  - JOYWR   - Joypad Output (bit0: ラッチ)
+// 4016h R  - JOYA    - Joypad Input Register A (ポート1 bit0=D0, bit1=D1)
+// 4017h R  - JOYB    - Joypad Input Register B (ポート2 bit0=D0, bit1=D1, bit2-4=1)
+// 4218h-421Fh RO - JOY1L/H .. JOY4L/H - 自動読み込みの結果
+
+use serde::{Deserialize, Serialize};
+
+// ポートにつなぐ機器
+pub trait Controller {
+  // 4016h bit0 の出力。1の間は入力を取り込み続け、0になると1bitずつ読み出せるようになる
+  fn latch(&mut self, strobe: bool);
+  // 1bit読み出す (bit0=D0, bit1=D1)
+  fn read(&mut self) -> u8;
+  // フロントエンドからの入力 (JOYxH/L と同じ並び)
+  fn set_buttons(&mut self, _buttons: u16) {}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Button {
+  B,
+  Y,
+  Select,
+  Start,
+  Up,
+  Down,
+  Left,
+  Right,
+  A,
+  X,
+  L,
+  R,
+}
+
+impl Button {
+  // 読み出し順 (B が最初) で bit15 から並ぶ。bit3-0 は ID (標準コントローラは0)
+  pub fn mask(&self) -> u16 {
+    match self {
+      Button::B => 0x8000,
+      Button::Y => 0x4000,
+      Button::Select => 0x2000,
+      Button::Start => 0x1000,
+      Button::Up => 0x0800,
+      Button::Down => 0x0400,
+      Button::Left => 0x0200,
+      Button::Right => 0x0100,
+      Button::A => 0x0080,
+      Button::X => 0x0040,
+      Button::L => 0x0020,
+      Button::R => 0x0010,
+    }
+  }
+}
+
+// 標準コントローラ。16bit読んだ後は1が返る
+pub struct Joypad {
+  buttons: u16,
+  shift: u16,
+  strobe: bool,
+}
+
+impl Joypad {
+  pub fn new() -> Self {
+    Self {
+      buttons: 0,
+      shift: 0,
+      strobe: false,
+    }
+  }
+}
+
+impl Controller for Joypad {
+  fn latch(&mut self, strobe: bool) {
+    self.strobe = strobe;
+    if strobe {
+      self.shift = self.buttons;
+    }
+  }
+
+  fn read(&mut self) -> u8 {
+    if self.strobe {
+      self.shift = self.buttons;
+    }
+    let bit = (self.shift >> 15) as u8;
+    self.shift = (self.shift << 1) | 0x0001;
+    bit
+  }
+
+  fn set_buttons(&mut self, buttons: u16) {
+    self.buttons = buttons;
+  }
+}
+
+// 何もつながっていないポート
+pub struct Unplugged;
+
+impl Controller for Unplugged {
+  fn latch(&mut self, _strobe: bool) {}
+  fn read(&mut self) -> u8 {
+    0
+  }
+}
+
+pub struct ControllerPorts {
+  ports: [Box<dyn Controller>; 2],
+  strobe: bool,
+  // JOY1..JOY4
+  joy: [u16; 4],
+}
+
+impl ControllerPorts {
+  pub fn new() -> Self {
+    Self {
+      ports: [Box::new(Joypad::new()), Box::new(Joypad::new())],
+      strobe: false,
+      joy: [0; 4],
+    }
+  }
+
+  pub fn connect(&mut self, port: usize, controller: Box<dyn Controller>) {
+    self.ports[port] = controller;
+  }
+
+  pub fn port_mut(&mut self, port: usize) -> &mut dyn Controller {
+    self.ports[port].as_mut()
+  }
+
+  pub fn write(&mut self, addr: u16, data: u8) {
+    match addr {
+      0x4016 => {
+        self.strobe = data & 0x01 != 0;
+        for port in self.ports.iter_mut() {
+          port.latch(self.strobe);
+        }
+      }
+      _ => panic!("not implemented ControllerPorts::write({:04X}, {:02X})", addr, data),
+    }
+  }
+
+  pub fn read(&mut self, addr: u16) -> u8 {
+    match addr {
+      0x4016 => self.ports[0].read() & 0x03,
+      0x4017 => (self.ports[1].read() & 0x03) | 0x1C,
+      0x4218..=0x421F => {
+        let joy = self.joy[((addr - 0x4218) / 2) as usize];
+        if addr & 0x01 == 0 { joy as u8 } else { (joy >> 8) as u8 }
+      }
+      _ => panic!("not implemented ControllerPorts::read({:04X})", addr),
+    }
+  }
+
+  // V-Blank開始時の自動読み込み (NMITIMEN bit0)
+  // ラッチしてから16回読み、D0 を JOY1/JOY2、D1 を JOY3/JOY4 に入れる
+  pub fn auto_read(&mut self) {
+    for port in self.ports.iter_mut() {
+      port.latch(true);
+      port.latch(false);
+    }
+    self.joy = [0; 4];
+    for _ in 0..16 {
+      for (i, port) in self.ports.iter_mut().enumerate() {
+        let bits = port.read();
+        self.joy[i] = (self.joy[i] << 1) | (bits & 0x01) as u16;
+        self.joy[i + 2] = (self.joy[i + 2] << 1) | ((bits >> 1) & 0x01) as u16;
+      }
+    }
+    // 4016h の出力は自動読み込みの後も元のまま
+    if self.strobe {
+      for port in self.ports.iter_mut() {
+        port.latch(true);
+      }
+    }
+  }
+}
@@ -560,7 +560,8 @@ impl CPU {
             Some(op) => {
                 // println!("{:06X} {}", pc, op.name);
                 call(self, &op);
-                self.bus.tick();
+                let op_cycles = if self.is_native_mode() { op.native.cycles } else { op.emulation.cycles };
+                self.bus.tick(op_cycles);
             }
             _ => {} // panic!("no implementation {:<02X}", opscode),
         }
@@ -0,0 +1,219 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use sdl3::event::Event;
+use sdl3::gamepad::{Button as GamepadButton, Gamepad};
+use sdl3::keyboard::Keycode;
+use sdl3::GamepadSubsystem;
+use serde::{Deserialize, Serialize};
+
+use crate::controller::Button;
+
+// SDL のキーボード/ゲームパッド入力を 1P/2P のコントローラに割り当てる
+
+// キー名は SDL_GetKeyFromName、ボタン名は SDL_GetGamepadButtonFromString の書式
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlayerBindings {
+  pub keyboard: HashMap<Button, String>,
+  pub gamepad: HashMap<Button, String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Bindings {
+  pub players: [PlayerBindings; 2],
+}
+
+impl Bindings {
+  pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+    let json = fs::read_to_string(path).map_err(|e| e.to_string())?;
+    serde_json::from_str(&json).map_err(|e| e.to_string())
+  }
+}
+
+fn bindings(pairs: &[(Button, &str)]) -> HashMap<Button, String> {
+  pairs.iter().map(|(button, name)| (*button, name.to_string())).collect()
+}
+
+impl Default for Bindings {
+  fn default() -> Self {
+    // ゲームパッドは位置で合わせる (下=B, 右=A, 左=Y, 上=X)
+    let gamepad = bindings(&[
+      (Button::B, "a"),
+      (Button::A, "b"),
+      (Button::Y, "x"),
+      (Button::X, "y"),
+      (Button::L, "leftshoulder"),
+      (Button::R, "rightshoulder"),
+      (Button::Select, "back"),
+      (Button::Start, "start"),
+      (Button::Up, "dpup"),
+      (Button::Down, "dpdown"),
+      (Button::Left, "dpleft"),
+      (Button::Right, "dpright"),
+    ]);
+    Self {
+      players: [
+        PlayerBindings {
+          keyboard: bindings(&[
+            (Button::B, "Z"),
+            (Button::A, "X"),
+            (Button::Y, "A"),
+            (Button::X, "S"),
+            (Button::L, "Q"),
+            (Button::R, "W"),
+            (Button::Select, "Right Shift"),
+            (Button::Start, "Return"),
+            (Button::Up, "Up"),
+            (Button::Down, "Down"),
+            (Button::Left, "Left"),
+            (Button::Right, "Right"),
+          ]),
+          gamepad: gamepad.clone(),
+        },
+        PlayerBindings {
+          keyboard: bindings(&[
+            (Button::B, "Keypad 1"),
+            (Button::A, "Keypad 3"),
+            (Button::Y, "Keypad 7"),
+            (Button::X, "Keypad 9"),
+            (Button::L, "Keypad /"),
+            (Button::R, "Keypad *"),
+            (Button::Select, "Keypad +"),
+            (Button::Start, "Keypad Enter"),
+            (Button::Up, "Keypad 8"),
+            (Button::Down, "Keypad 2"),
+            (Button::Left, "Keypad 4"),
+            (Button::Right, "Keypad 6"),
+          ]),
+          gamepad,
+        },
+      ],
+    }
+  }
+}
+
+pub struct Input {
+  keys: HashMap<Keycode, Vec<(usize, Button)>>,
+  // キーボードの割り当てがあるプレイヤー
+  has_keyboard: [bool; 2],
+  pad_buttons: [HashMap<GamepadButton, Button>; 2],
+  gamepad_subsystem: GamepadSubsystem,
+  // 1P, 2P に割り当てたゲームパッド。外しても他のプレイヤーの割り当ては変わらない
+  gamepads: [Option<Gamepad>; 2],
+  // キーボードとゲームパッドの入力は別々に持って OR する (JOYxH/L と同じ並び)
+  keyboard: [u16; 2],
+  pad: [u16; 2],
+}
+
+impl Input {
+  pub fn new(bindings: &Bindings, gamepad_subsystem: GamepadSubsystem) -> Self {
+    let mut keys: HashMap<Keycode, Vec<(usize, Button)>> = HashMap::new();
+    let mut pad_buttons: [HashMap<GamepadButton, Button>; 2] = [HashMap::new(), HashMap::new()];
+    for (player, binding) in bindings.players.iter().enumerate() {
+      for (button, name) in &binding.keyboard {
+        match Keycode::from_name(name) {
+          Some(keycode) => keys.entry(keycode).or_default().push((player, *button)),
+          None => println!("unknown key name: {}", name),
+        }
+      }
+      for (button, name) in &binding.gamepad {
+        match GamepadButton::from_string(name) {
+          Some(pad_button) => {
+            pad_buttons[player].insert(pad_button, *button);
+          }
+          None => println!("unknown gamepad button name: {}", name),
+        }
+      }
+    }
+
+    let mut has_keyboard = [false; 2];
+    for &(player, _) in keys.values().flatten() {
+      has_keyboard[player] = true;
+    }
+    let mut input = Self {
+      keys,
+      has_keyboard,
+      pad_buttons,
+      gamepad_subsystem,
+      gamepads: [None, None],
+      keyboard: [0; 2],
+      pad: [0; 2],
+    };
+    if let Ok(ids) = input.gamepad_subsystem.gamepads() {
+      for id in ids {
+        input.open_gamepad(id.0);
+      }
+    }
+    input
+  }
+
+  // 空いているプレイヤーに割り当てる
+  fn open_gamepad(&mut self, which: u32) {
+    if self.gamepad_player(which).is_some() {
+      return
+    }
+    let Some(player) = self.gamepads.iter().position(|gamepad| gamepad.is_none()) else {
+      return
+    };
+    match self.gamepad_subsystem.open(sdl3::sys::joystick::SDL_JoystickID(which)) {
+      Ok(gamepad) => self.gamepads[player] = Some(gamepad),
+      Err(e) => println!("failed to open gamepad: {}", e),
+    }
+  }
+
+  fn gamepad_player(&self, which: u32) -> Option<usize> {
+    self.gamepads.iter().position(|gamepad| {
+      gamepad.as_ref().is_some_and(|gamepad| gamepad.id().is_ok_and(|id| id.0 == which))
+    })
+  }
+
+  fn set(state: &mut u16, button: Button, pressed: bool) {
+    if pressed {
+      *state |= button.mask();
+    } else {
+      *state &= !button.mask();
+    }
+  }
+
+  // キーボードの割り当ても、ゲームパッドも無いプレイヤーのポートは何もつながっていない
+  pub fn connected(&self, player: usize) -> bool {
+    self.has_keyboard[player] || self.gamepads[player].is_some()
+  }
+
+  // JOYxH/L と同じ並び
+  pub fn buttons(&self, player: usize) -> u16 {
+    self.keyboard[player] | self.pad[player]
+  }
+
+  pub fn handle_event(&mut self, event: &Event) {
+    match event {
+      Event::KeyDown { keycode: Some(keycode), repeat: false, .. } => {
+        for (player, button) in self.keys.get(keycode).cloned().unwrap_or_default() {
+          Self::set(&mut self.keyboard[player], button, true);
+        }
+      }
+      Event::KeyUp { keycode: Some(keycode), .. } => {
+        for (player, button) in self.keys.get(keycode).cloned().unwrap_or_default() {
+          Self::set(&mut self.keyboard[player], button, false);
+        }
+      }
+      Event::ControllerDeviceAdded { which, .. } => self.open_gamepad(*which),
+      Event::ControllerDeviceRemoved { which, .. } => {
+        if let Some(player) = self.gamepad_player(*which) {
+          self.gamepads[player] = None;
+          self.pad[player] = 0;
+        }
+      }
+      Event::ControllerButtonDown { which, button, .. } | Event::ControllerButtonUp { which, button, .. } => {
+        let pressed = matches!(event, Event::ControllerButtonDown { .. });
+        if let Some(player) = self.gamepad_player(*which) {
+          if let Some(&snes_button) = self.pad_buttons[player].get(button) {
+            Self::set(&mut self.pad[player], snes_button, pressed);
+          }
+        }
+      }
+      _ => {}
+    }
+  }
+}
@@ -4,11 +4,18 @@ mod ppu;
 mod bus;
 mod cartridge;
 mod apu;
-
-use cartridge::Cartridge;
-use bus::Bus;
+mod alu;
+mod controller;
+mod input;
+mod patch;
+mod archive;
+
+use cartridge::{Cartridge, VideoStandard};
+use bus::{Bus, UnmappedPolicy};
 use cpu::CPU;
 use ppu::PPU;
+use input::{Bindings, Input};
+use controller::{Joypad, Unplugged};
 
 use sdl3::pixels::{Color, PixelFormat};
 use sdl3::event::Event;
@@ -36,36 +43,124 @@ fn main() {
 
   let mut event_pump = sdl_context.event_pump().unwrap();
 
-  // let cartridge = Cartridge::new("rom/SNES/TEST/cputest.sfc");
-  // let cartridge = Cartridge::new("rom/SNES/TEST/gradient-test.sfc");
-  let cartridge = Cartridge::new("rom/SNES/TEST/ppubusact.sfc");
-  // let mut cartridge = Cartridge::new("rom/SNES/ROM/CHRONO TRIGGER/50/Chrono Trigger (Japan).sfc");
-  let cartridge = Cartridge::new("rom/SNES/ROM/SUPERMARIO COLLECTION/61/Super Mario Collection (Japan).sfc");
-  // let cartridge = Cartridge::new("rom/SNES/ROM/SUPER BOMBERMAN/77/Super Bomberman (Japan).sfc");
-  let ppu = PPU::new();
-  let bus = Bus::new(
+  // let filename = "rom/SNES/TEST/cputest.sfc";
+  // let filename = "rom/SNES/TEST/gradient-test.sfc";
+  // let filename = "rom/SNES/TEST/ppubusact.sfc";
+  // let filename = "rom/SNES/ROM/CHRONO TRIGGER/50/Chrono Trigger (Japan).sfc";
+  // let filename = "rom/SNES/ROM/SUPERMARIO COLLECTION/61/Super Mario Collection (Japan).sfc";
+  // let filename = "rom/SNES/ROM/SUPER BOMBERMAN/77/Super Bomberman (Japan).sfc";
+
+  // usage: main [rom(.sfc/.smc/.zip/.gz)] [--entry <name in zip>] [--patch <ips/ups/bps>] [--region <ntsc/pal>] [--input <bindings.json>] [--unmapped <ignore/log/break>]
+  let mut filename = String::from("rom/SNES/ROM/SUPERMARIO COLLECTION/61/Super Mario Collection (Japan).sfc");
+  let mut entry: Option<String> = None;
+  let mut patch: Option<String> = None;
+  let mut region: Option<VideoStandard> = None;
+  let mut input_config: Option<String> = None;
+  let mut unmapped_policy: Option<UnmappedPolicy> = None;
+  let mut args = std::env::args().skip(1);
+  while let Some(arg) = args.next() {
+    match arg.as_str() {
+      "--entry" => entry = args.next(),
+      "--patch" => patch = args.next(),
+      "--input" => input_config = args.next(),
+      "--unmapped" => {
+        unmapped_policy = match args.next().as_deref() {
+          Some("ignore") => Some(UnmappedPolicy::Ignore),
+          Some("log") => Some(UnmappedPolicy::LogOnce),
+          Some("break") => Some(UnmappedPolicy::Break),
+          _ => None,
+        }
+      }
+      "--region" => {
+        region = match args.next().as_deref() {
+          Some("ntsc") => Some(VideoStandard::Ntsc),
+          Some("pal") => Some(VideoStandard::Pal),
+          _ => None,
+        }
+      }
+      _ => filename = arg,
+    }
+  }
+
+  let opened = match (&entry, &patch) {
+    (None, None) => Cartridge::open(&filename),
+    _ => Cartridge::open_with(&filename, entry.as_deref(), patch),
+  };
+  let cartridge = match opened {
+    Ok(cartridge) => cartridge,
+    Err(e) => {
+      println!("{}: {}", filename, e);
+      std::process::exit(1);
+    }
+  };
+  println!("{}", serde_json::to_string(cartridge.info()).unwrap());
+  println!(
+    "mapping: {:?}, header: {:06X}, copier header: {}",
+    cartridge.mapping(), cartridge.header_offset(), cartridge.had_copier_header()
+  );
+  // 指定が無ければヘッダの国コードから決める
+  let mut ppu = PPU::new();
+  ppu.set_video_standard(region.unwrap_or(cartridge.info().video_standard));
+  let mut bus = Bus::new(
     ppu,
     cartridge,
   );
+  if let Some(policy) = unmapped_policy {
+    bus.unmapped_policy = policy;
+  }
   let mut cpu = CPU::new(bus);
 
+  // キー割り当て (指定が無ければデフォルト)
+  let bindings = match &input_config {
+    Some(path) => Bindings::load(path).unwrap_or_else(|e| {
+      println!("{}: {}", path, e);
+      Bindings::default()
+    }),
+    None => Bindings::default(),
+  };
+  let mut input = Input::new(&bindings, sdl_context.gamepad().unwrap());
+  // 起動時は両方のポートに標準コントローラがつながっている
+  let mut connected = [true; 2];
+
   cpu.reset();
   let mut frame = 0;
 
   'running: loop {
     cpu.run();
+    if let Some(access) = cpu.bus.unmapped_break.take() {
+      println!("break: {} (PC={:02X}:{:04X})", access, cpu.program_bank, cpu.program_counter);
+      break 'running
+    }
     if cpu.bus.ppu.frame_updated {
       cpu.bus.ppu.frame_updated = false;
       frame += 1;
 
+      // 5秒ごとにSRAMを書き出す
+      if frame % 300 == 0 {
+        if let Err(e) = cpu.bus.cartridge.save_sram() {
+          println!("failed to save SRAM: {}", e);
+        }
+      }
+
       for event in event_pump.poll_iter() {
         match event {
           Event::Quit {..} |
           Event::KeyDown { keycode: Some(Keycode::Escape), .. } => {
               break 'running
           },
-          _ => {}
+          _ => input.handle_event(&event),
+        }
+      }
+      for (port, connected) in connected.iter_mut().enumerate() {
+        if input.connected(port) != *connected {
+          *connected = input.connected(port);
+          if *connected {
+            cpu.bus.controllers.connect(port, Box::new(Joypad::new()));
+          } else {
+            cpu.bus.controllers.connect(port, Box::new(Unplugged));
+          }
         }
+        cpu.bus.controllers.port_mut(port).set_buttons(input.buttons(port));
       }
 
       canvas.clear();
@@ -80,4 +175,8 @@ fn main() {
 
     // ::std::thread::sleep(Duration::new(0,   1_000_000_000u32 / 60));
   }
+
+  if let Err(e) = cpu.bus.cartridge.save_sram() {
+    println!("failed to save SRAM: {}", e);
+  }
 }
@@ -0,0 +1,472 @@
+use std::fmt;
+
+// ROM読み込み時に当てるソフトパッチ (IPS / UPS / BPS)
+
+#[derive(Debug)]
+pub enum PatchError {
+  UnknownFormat,
+  Corrupt(&'static str),
+  SourceChecksumMismatch { expected: u32, actual: u32 },
+  TargetChecksumMismatch { expected: u32, actual: u32 },
+  PatchChecksumMismatch { expected: u32, actual: u32 },
+}
+
+impl fmt::Display for PatchError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      PatchError::UnknownFormat => write!(f, "unknown patch format"),
+      PatchError::Corrupt(reason) => write!(f, "corrupt patch: {}", reason),
+      PatchError::SourceChecksumMismatch { expected, actual } => {
+        write!(f, "source CRC32 mismatch (expected {:08X}, got {:08X})", expected, actual)
+      }
+      PatchError::TargetChecksumMismatch { expected, actual } => {
+        write!(f, "target CRC32 mismatch (expected {:08X}, got {:08X})", expected, actual)
+      }
+      PatchError::PatchChecksumMismatch { expected, actual } => {
+        write!(f, "patch CRC32 mismatch (expected {:08X}, got {:08X})", expected, actual)
+      }
+    }
+  }
+}
+
+impl std::error::Error for PatchError {}
+
+// 先頭のマジックで形式を判定して当てる
+// copier_header: ROM から取り除いたコピアーヘッダのサイズ。
+// IPS はヘッダ付きのダンプに対して作られているものとして、その分オフセットをずらす (snes9x と同じ)
+pub fn apply(rom: &[u8], patch: &[u8], copier_header: usize) -> Result<Vec<u8>, PatchError> {
+  if patch.starts_with(b"PATCH") {
+    apply_ips(rom, patch, copier_header)
+  } else if patch.starts_with(b"UPS1") {
+    apply_ups(rom, patch)
+  } else if patch.starts_with(b"BPS1") {
+    apply_bps(rom, patch)
+  } else {
+    Err(PatchError::UnknownFormat)
+  }
+}
+
+struct Reader<'a> {
+  data: &'a [u8],
+  pos: usize,
+}
+
+impl<'a> Reader<'a> {
+  fn new(data: &'a [u8], pos: usize) -> Self {
+    Self { data, pos }
+  }
+
+  fn u8(&mut self) -> Result<u8, PatchError> {
+    let v = *self.data.get(self.pos).ok_or(PatchError::Corrupt("unexpected end of patch"))?;
+    self.pos += 1;
+    Ok(v)
+  }
+
+  fn bytes(&mut self, len: usize) -> Result<&'a [u8], PatchError> {
+    let end = self.pos.checked_add(len).filter(|&end| end <= self.data.len());
+    let end = end.ok_or(PatchError::Corrupt("unexpected end of patch"))?;
+    let v = &self.data[self.pos..end];
+    self.pos = end;
+    Ok(v)
+  }
+
+  fn u16_be(&mut self) -> Result<usize, PatchError> {
+    Ok((self.u8()? as usize) << 8 | self.u8()? as usize)
+  }
+
+  fn u24_be(&mut self) -> Result<usize, PatchError> {
+    Ok((self.u8()? as usize) << 16 | (self.u8()? as usize) << 8 | self.u8()? as usize)
+  }
+
+  // UPS/BPS の可変長整数
+  fn varint(&mut self) -> Result<usize, PatchError> {
+    let mut value: usize = 0;
+    let mut shift: usize = 1;
+    loop {
+      let x = self.u8()?;
+      value = value
+        .checked_add((x & 0x7F) as usize * shift)
+        .ok_or(PatchError::Corrupt("number overflow"))?;
+      if x & 0x80 != 0 {
+        return Ok(value)
+      }
+      shift = shift.checked_shl(7).ok_or(PatchError::Corrupt("number overflow"))?;
+      value = value.checked_add(shift).ok_or(PatchError::Corrupt("number overflow"))?;
+    }
+  }
+}
+
+// IPS
+// "PATCH" + { offset(3) size(2) data[size] | offset(3) 0000 count(2) value(1) }* + "EOF" [+ truncate(3)]
+fn apply_ips(rom: &[u8], patch: &[u8], copier_header: usize) -> Result<Vec<u8>, PatchError> {
+  let mut target = rom.to_vec();
+  let mut r = Reader::new(patch, 5);
+  loop {
+    let offset = r.u24_be()?;
+    if offset == 0x454F46 {
+      // "EOF"
+      break
+    }
+    let size = r.u16_be()?;
+    let data = if size == 0 {
+      // RLE
+      let count = r.u16_be()?;
+      let value = r.u8()?;
+      vec![value; count]
+    } else {
+      r.bytes(size)?.to_vec()
+    };
+    // コピアーヘッダへの書き込みは捨てる
+    let (offset, data) = if offset < copier_header {
+      let skip = (copier_header - offset).min(data.len());
+      (0, &data[skip..])
+    } else {
+      (offset - copier_header, &data[..])
+    };
+    if data.is_empty() {
+      continue;
+    }
+    if target.len() < offset + data.len() {
+      target.resize(offset + data.len(), 0);
+    }
+    target[offset..offset + data.len()].copy_from_slice(data);
+  }
+  // lunar ips の切り詰め拡張
+  if let Ok(size) = r.u24_be() {
+    target.truncate(size.saturating_sub(copier_header));
+  }
+  Ok(target)
+}
+
+fn read_footer(patch: &[u8], magic_len: usize) -> Result<(u32, u32), PatchError> {
+  if patch.len() < magic_len + 12 {
+    return Err(PatchError::Corrupt("patch too small"))
+  }
+  let footer = &patch[patch.len() - 12..];
+  let le = |b: &[u8]| u32::from_le_bytes([b[0], b[1], b[2], b[3]]);
+  let source_crc = le(&footer[0..4]);
+  let target_crc = le(&footer[4..8]);
+  let patch_crc = le(&footer[8..12]);
+
+  let actual = crc32(&patch[..patch.len() - 4]);
+  if actual != patch_crc {
+    return Err(PatchError::PatchChecksumMismatch { expected: patch_crc, actual })
+  }
+  Ok((source_crc, target_crc))
+}
+
+fn check_source(rom: &[u8], expected: u32) -> Result<(), PatchError> {
+  let actual = crc32(rom);
+  if actual != expected {
+    return Err(PatchError::SourceChecksumMismatch { expected, actual })
+  }
+  Ok(())
+}
+
+fn check_target(target: &[u8], expected: u32) -> Result<(), PatchError> {
+  let actual = crc32(target);
+  if actual != expected {
+    return Err(PatchError::TargetChecksumMismatch { expected, actual })
+  }
+  Ok(())
+}
+
+// UPS
+// "UPS1" + source_size + target_size + { skip xor[..] 00 }* + source_crc + target_crc + patch_crc
+fn apply_ups(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+  let (source_crc, target_crc) = read_footer(patch, 4)?;
+  check_source(rom, source_crc)?;
+
+  let end = patch.len() - 12;
+  let mut r = Reader::new(&patch[..end], 4);
+  let source_size = r.varint()?;
+  let target_size = r.varint()?;
+  if source_size != rom.len() {
+    return Err(PatchError::Corrupt("source size does not match"))
+  }
+
+  let mut target = vec![0; target_size];
+  let len = rom.len().min(target_size);
+  target[..len].copy_from_slice(&rom[..len]);
+
+  let mut pos = 0;
+  while r.pos < end {
+    pos += r.varint()?;
+    loop {
+      let x = r.u8()?;
+      if pos < target.len() {
+        target[pos] ^= x;
+      }
+      pos += 1;
+      if x == 0 {
+        break
+      }
+    }
+  }
+
+  check_target(&target, target_crc)?;
+  Ok(target)
+}
+
+// BPS
+// action: data = (length - 1) << 2 | command
+// 0=SourceRead, 1=TargetRead, 2=SourceCopy, 3=TargetCopy
+// "BPS1" + source_size + target_size + metadata_size + metadata + actions + source_crc + target_crc + patch_crc
+fn apply_bps(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+  let (source_crc, target_crc) = read_footer(patch, 4)?;
+  check_source(rom, source_crc)?;
+
+  let end = patch.len() - 12;
+  let mut r = Reader::new(&patch[..end], 4);
+  let source_size = r.varint()?;
+  let target_size = r.varint()?;
+  let metadata_size = r.varint()?;
+  r.bytes(metadata_size)?;
+  if source_size != rom.len() {
+    return Err(PatchError::Corrupt("source size does not match"))
+  }
+
+  let mut target = vec![0; target_size];
+  let mut output = 0usize;
+  let mut source_relative = 0isize;
+  let mut target_relative = 0isize;
+
+  while r.pos < end {
+    let data = r.varint()?;
+    let command = data & 0x03;
+    let length = (data >> 2) + 1;
+    if output + length > target.len() {
+      return Err(PatchError::Corrupt("write past end of target"))
+    }
+    match command {
+      // SourceRead
+      0 => {
+        if output + length > rom.len() {
+          return Err(PatchError::Corrupt("read past end of source"))
+        }
+        target[output..output + length].copy_from_slice(&rom[output..output + length]);
+      }
+      // TargetRead
+      1 => {
+        target[output..output + length].copy_from_slice(r.bytes(length)?);
+      }
+      // SourceCopy / TargetCopy
+      _ => {
+        let offset = r.varint()?;
+        let delta = (offset >> 1) as isize;
+        let delta = if offset & 1 != 0 { -delta } else { delta };
+        if command == 2 {
+          source_relative += delta;
+          let from = source_relative as usize;
+          if source_relative < 0 || from + length > rom.len() {
+            return Err(PatchError::Corrupt("read past end of source"))
+          }
+          target[output..output + length].copy_from_slice(&rom[from..from + length]);
+          source_relative += length as isize;
+        } else {
+          target_relative += delta;
+          if target_relative < 0 || target_relative as usize >= output {
+            return Err(PatchError::Corrupt("read past end of target"))
+          }
+          // 重なっている場合があるので1バイトずつコピーする
+          for i in 0..length {
+            target[output + i] = target[target_relative as usize + i];
+          }
+          target_relative += length as isize;
+        }
+      }
+    }
+    output += length;
+  }
+
+  check_target(&target, target_crc)?;
+  Ok(target)
+}
+
+// CRC32 (IEEE 802.3)
+pub fn crc32(data: &[u8]) -> u32 {
+  let mut table = [0u32; 256];
+  for (i, entry) in table.iter_mut().enumerate() {
+    let mut c = i as u32;
+    for _ in 0..8 {
+      c = if c & 1 != 0 { 0xEDB88320 ^ (c >> 1) } else { c >> 1 };
+    }
+    *entry = c;
+  }
+  !data.iter().fold(!0u32, |crc, &b| table[((crc ^ b as u32) & 0xFF) as usize] ^ (crc >> 8))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // UPS/BPS の可変長整数
+  fn varint(mut value: usize) -> Vec<u8> {
+    let mut out = vec![];
+    loop {
+      let x = (value & 0x7F) as u8;
+      value >>= 7;
+      if value == 0 {
+        out.push(0x80 | x);
+        return out
+      }
+      out.push(x);
+      value -= 1;
+    }
+  }
+
+  // source_crc + target_crc + patch_crc
+  fn with_footer(mut patch: Vec<u8>, source: &[u8], target: &[u8]) -> Vec<u8> {
+    patch.extend(crc32(source).to_le_bytes());
+    patch.extend(crc32(target).to_le_bytes());
+    patch.extend(crc32(&patch).to_le_bytes());
+    patch
+  }
+
+  fn ips(records: &[u8]) -> Vec<u8> {
+    [b"PATCH", records, b"EOF"].concat()
+  }
+
+  #[test]
+  fn crc32_check_value() {
+    assert_eq!(crc32(b"123456789"), 0xCBF43926);
+  }
+
+  #[test]
+  fn unknown_format() {
+    assert!(matches!(apply(b"ABCD", b"NOTAPATCH", 0), Err(PatchError::UnknownFormat)));
+  }
+
+  #[test]
+  fn ips_records_and_rle() {
+    let patch = ips(&[
+      0x00, 0x00, 0x01, 0x00, 0x02, b'x', b'y',
+      // RLE
+      0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x03, b'z',
+    ]);
+    assert_eq!(apply(b"ABCDEFGH", &patch, 0).unwrap(), b"AxyDzzzH");
+  }
+
+  #[test]
+  fn ips_extends_rom() {
+    let patch = ips(&[0x00, 0x00, 0x06, 0x00, 0x01, b'!']);
+    assert_eq!(apply(b"ABCD", &patch, 0).unwrap(), b"ABCD\0\0!");
+  }
+
+  #[test]
+  fn ips_truncate_after_eof() {
+    let mut patch = ips(&[0x00, 0x00, 0x01, 0x00, 0x01, b'x']);
+    patch.extend([0x00, 0x00, 0x03]);
+    assert_eq!(apply(b"ABCDEFGH", &patch, 0).unwrap(), b"AxC");
+  }
+
+  #[test]
+  fn ips_missing_eof() {
+    let patch = [b"PATCH".as_slice(), &[0x00, 0x00, 0x01, 0x00, 0x04, b'x']].concat();
+    assert!(matches!(apply(b"ABCDEFGH", &patch, 0), Err(PatchError::Corrupt(_))));
+  }
+
+  #[test]
+  fn ips_copier_header_offset() {
+    let patch = ips(&[
+      // ヘッダだけに書き込むレコードは捨てる
+      0x00, 0x00, 0x10, 0x00, 0x01, b'h',
+      // ヘッダをまたぐレコードはヘッダの外側だけ当てる
+      0x00, 0x01, 0xFF, 0x00, 0x02, b'q', b'r',
+      0x00, 0x02, 0x02, 0x00, 0x01, b's',
+    ]);
+    assert_eq!(apply(b"ABCD", &patch, 0x200).unwrap(), b"rBsD");
+  }
+
+  #[test]
+  fn varint_decoding() {
+    assert_eq!(varint(0), [0x80]);
+    assert_eq!(varint(127), [0xFF]);
+    assert_eq!(varint(128), [0x00, 0x80]);
+    for value in [0, 1, 127, 128, 300, 16511, 16512, 1 << 20, 0x7FFF_FFFF] {
+      let data = varint(value);
+      let mut r = Reader::new(&data, 0);
+      assert_eq!(r.varint().unwrap(), value);
+      assert_eq!(r.pos, data.len());
+    }
+  }
+
+  fn ups_patch() -> Vec<u8> {
+    let mut patch = b"UPS1".to_vec();
+    patch.extend(varint(4));
+    patch.extend(varint(5));
+    // 2 バイト飛ばして C => Z
+    patch.extend(varint(2));
+    patch.extend([b'C' ^ b'Z', 0x00]);
+    // 元のサイズより後ろに E を足す
+    patch.extend(varint(0));
+    patch.extend([b'E', 0x00]);
+    with_footer(patch, b"ABCD", b"ABZDE")
+  }
+
+  #[test]
+  fn ups_apply() {
+    assert_eq!(apply(b"ABCD", &ups_patch(), 0).unwrap(), b"ABZDE");
+  }
+
+  #[test]
+  fn ups_source_mismatch() {
+    let result = apply(b"ABCE", &ups_patch(), 0);
+    assert!(matches!(result, Err(PatchError::SourceChecksumMismatch { .. })));
+  }
+
+  #[test]
+  fn ups_patch_checksum_mismatch() {
+    let mut patch = ups_patch();
+    patch[6] ^= 0x01;
+    assert!(matches!(apply(b"ABCD", &patch, 0), Err(PatchError::PatchChecksumMismatch { .. })));
+  }
+
+  fn bps_patch(target: &[u8]) -> Vec<u8> {
+    let mut patch = b"BPS1".to_vec();
+    patch.extend(varint(4));
+    patch.extend(varint(8));
+    patch.extend(varint(0));
+    // SourceRead 2
+    patch.extend(varint(1 << 2));
+    // TargetRead 1
+    patch.extend(varint(1));
+    patch.push(b'X');
+    // TargetCopy 5 (+2)。書き込み中の範囲と重なるので X が繰り返される
+    patch.extend(varint((4 << 2) | 3));
+    patch.extend(varint(2 << 1));
+    with_footer(patch, b"ABCD", target)
+  }
+
+  #[test]
+  fn bps_target_copy_overlap() {
+    assert_eq!(apply(b"ABCD", &bps_patch(b"ABXXXXXX"), 0).unwrap(), b"ABXXXXXX");
+  }
+
+  #[test]
+  fn bps_source_copy() {
+    let mut patch = b"BPS1".to_vec();
+    patch.extend(varint(4));
+    patch.extend(varint(4));
+    patch.extend(varint(0));
+    // SourceCopy 2 (+2), SourceCopy 2 (-4)
+    patch.extend(varint((1 << 2) | 2));
+    patch.extend(varint(2 << 1));
+    patch.extend(varint((1 << 2) | 2));
+    patch.extend(varint((4 << 1) | 1));
+    let patch = with_footer(patch, b"ABCD", b"CDAB");
+    assert_eq!(apply(b"ABCD", &patch, 0).unwrap(), b"CDAB");
+  }
+
+  #[test]
+  fn bps_target_mismatch() {
+    let result = apply(b"ABCD", &bps_patch(b"ABXXXXXY"), 0);
+    assert!(matches!(result, Err(PatchError::TargetChecksumMismatch { .. })));
+  }
+
+  #[test]
+  fn bps_source_mismatch() {
+    let result = apply(b"ABCE", &bps_patch(b"ABXXXXXX"), 0);
+    assert!(matches!(result, Err(PatchError::SourceChecksumMismatch { .. })));
+  }
+}
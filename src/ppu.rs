@@ -1,6 +1,8 @@
 use core::panic;
 use std::ops::{Range, RangeInclusive};
 
+use crate::cartridge::VideoStandard;
+
 fn bgr555_to_rgb888(data: u16) -> [u8; 3] {
   // .BBB BBGG GGGR RRRR
   let r = (data & 0x001F) as u8;
@@ -14,32 +16,94 @@ fn bgr555_to_rgb888(data: u16) -> [u8; 3] {
 
 pub struct PPU {
   cycles: u32,
+  video_standard: VideoStandard,
   // registers
   pub inidisp: u8, // 2100h WO - INIDISP - ディスプレイ制御レジスタ1
   pub obsel: u8, // 2101h WO - OBSEL   - Object Size and Object Base
   pub oamaddl: u8, // 2102h WO - OAMADDL - OAMアドレス (下位8bit)
-  pub oamaddh: u8,// 2103h WO - OAMADDH - OAMアドレス (上位1bit)
-  pub oamdata: u8, // 2104h WO - OAMDATA - OAM書き込み
+  pub oamaddh: u8,// 2103h WO - OAMADDH - OAMアドレス (上位1bit, bit7: 優先度ローテーション)
+  // 2104h WO - OAMDATA - OAM書き込み
+  // 下位テーブル 512バイト (1スプライト 4バイト x 128) + 上位テーブル 32バイト (1スプライト 2bit)
+  oam: Vec<u8>,
+  // OAM の内部アドレス (バイト単位、10bit)
+  oam_addr: u16,
+  // 下位テーブルは偶数アドレスの書き込みを覚えておき、奇数アドレスの書き込みで 2バイトまとめて書き込む
+  oam_latch: u8,
   pub bgmode: u8, // 2105h WO - BGMODE  - BG制御レジスタ
-  pub mosaic: u8, // 2106h WO - MOSAIC  - モザイク
+  pub mosaic: u8, // 2106h WO - MOSAIC  - モザイク (bit7-4: サイズ-1, bit3-0: BG4-BG1 有効)
+  // モザイクの縦方向のブロック。ライン1 (表示の先頭) でリセットされ、
+  // モザイクが無効でもサイズ分のラインごとに次のブロックへ進む
+  mosaic_vcounter: u16,
+  mosaic_start_line: u16,
   pub bg1sc: u8, // 2107h WO - BG1SC   - BG1画面設定
+  pub bg2sc: u8, // 2108h WO - BG2SC   - BG2画面設定
+  pub bg3sc: u8, // 2109h WO - BG3SC   - BG3画面設定
+  pub bg4sc: u8, // 210Ah WO - BG4SC   - BG4画面設定
   pub bg12nba: u8, // 210Bh WO - BG12NBA - BG1,2タイルデータアドレス
-  // 2byteあるっぽい
+  pub bg34nba: u8, // 210Ch WO - BG34NBA - BG3,4タイルデータアドレス
+  // 2回書き込みで 10bit。前回書き込んだ値 (bg_ofs_latch) は BG1-4 で共通
   // BGnHOFS の場合 : (NewByte<<8) | (PrevByte&~7) | ((CurrentValue>>8)&7)
   // BGnVOFS の場合 : (NewByte<<8) | PrevByte
-  pub bg1hofs: u8, // 210Dh WO - BG1HOFS - BG1Xスクロール / M7HOFS
-  pub bg1vofs: u8, // 210Eh WO - BG1VOFS - BG1Yスクロール / M7VOFS
+  // 210Dh WO - BG1HOFS - BG1Xスクロール / M7HOFS
+  // 210Fh WO - BG2HOFS - BG2Xスクロール
+  // 2111h WO - BG3HOFS - BG3Xスクロール
+  // 2113h WO - BG4HOFS - BG4Xスクロール
+  pub bg_hofs: [u16; 4],
+  // 210Eh WO - BG1VOFS - BG1Yスクロール / M7VOFS
+  // 2110h WO - BG2VOFS - BG2Yスクロール
+  // 2112h WO - BG3VOFS - BG3Yスクロール
+  // 2114h WO - BG4VOFS - BG4Yスクロール
+  pub bg_vofs: [u16; 4],
+  bg_ofs_latch: u8,
+  // 210Dh/210Eh は Mode 7 のスクロール (13bit 符号付き) にも同時に書き込まれる
+  // M7HOFS/M7VOFS : (NewByte<<8) | PrevByte (PrevByte は Mode 7 のレジスタで共通)
+  pub m7hofs: u16,
+  pub m7vofs: u16,
+  m7_latch: u8,
+  // 211Ah WO - M7SEL   - Mode 7 設定
+  // 7-6 画面外 (0/1=繰り返し, 2=透明, 3=タイル0で埋める)、1 上下反転、0 左右反転
+  m7sel: u8,
+  // 211Bh WO - M7A     - Mode 7 行列 A (16bit 符号付き) / 乗算の被乗数
+  // 211Ch WO - M7B     - Mode 7 行列 B (16bit 符号付き) / 乗算の乗数 (上位8bit)
+  // 211Dh WO - M7C     - Mode 7 行列 C (16bit 符号付き)
+  // 211Eh WO - M7D     - Mode 7 行列 D (16bit 符号付き)
+  m7a: u16,
+  m7b: u16,
+  m7c: u16,
+  m7d: u16,
+  // 211Fh WO - M7X     - Mode 7 中心 X (13bit 符号付き)
+  // 2120h WO - M7Y     - Mode 7 中心 Y (13bit 符号付き)
+  m7x: u16,
+  m7y: u16,
+  // 2134h RO - MPYL    - 乗算結果 (下位8bit)
+  // 2135h RO - MPYM    - 乗算結果 (中位8bit)
+  // 2136h RO - MPYH    - 乗算結果 (上位8bit)
+  mpy: i32,
 
   pub vmain: u8, // 2115h WO - VMAIN   - VRAMアドレス増加レジスタ
   cgadd: u8, // 2121h WO - CGADD   - パレットアドレス
   cg_write_low: bool,
   // cgdata: Vec<u8>, // 2122h WO - CGDATA  - パレット書き込み
   cgdata: Vec<u16>, // 2122h WO - CGDATA  - パレット書き込み
+  // ウィンドウ設定 (レイヤごとに 4bit)
+  // bit0: ウィンドウ1 反転, bit1: ウィンドウ1 有効, bit2: ウィンドウ2 反転, bit3: ウィンドウ2 有効
+  w12sel: u8, // 2123h WO - W12SEL  - BG1/BG2 ウィンドウ設定
+  w34sel: u8, // 2124h WO - W34SEL  - BG3/BG4 ウィンドウ設定
+  wobjsel: u8, // 2125h WO - WOBJSEL - OBJ/色ウィンドウ設定
+  wh0: u8, // 2126h WO - WH0     - ウィンドウ1 左端
+  wh1: u8, // 2127h WO - WH1     - ウィンドウ1 右端
+  wh2: u8, // 2128h WO - WH2     - ウィンドウ2 左端
+  wh3: u8, // 2129h WO - WH3     - ウィンドウ2 右端
+  // ウィンドウ1/2 の組み合わせ (レイヤごとに 2bit、0=OR, 1=AND, 2=XOR, 3=XNOR)
+  wbglog: u8, // 212Ah WO - WBGLOG  - BG1-4 ウィンドウ論理
+  wobjlog: u8, // 212Bh WO - WOBJLOG - OBJ/色ウィンドウ論理
   tm: u8, // 212Ch WO - TM      - メイン画面レイヤ制御
   ts: u8, // 212Dh WO - TS      - サブ画面レイヤ制御
   tmw: u8, // 212Eh WO - TMW     - Window Area Main Screen Disable
+  tsw: u8, // 212Fh WO - TSW     - Window Area Sub Screen Disable
   cgwsel: u8, // 2130h WO - CGWSEL  - ColorMath制御レジスタA
   cgadsub: u8, // 2131h WO - CGADSUB - ColorMath制御レジスタB
+  coldata: u16, // 2132h WO - COLDATA - 固定色 (BGR555)
   setini: u8, // 2133h WO - SETINI  - ディスプレイ制御レジスタ2
   // 2116h WO - VMADDL  - VRAMアドレス (下位8bit)
   // 2117h WO - VMADDH  - VRAMアドレス (上位8bit)
@@ -47,6 +111,8 @@ pub struct PPU {
   // 2118h WO - VMDATAL - VRAMデータ書き込み (下位8bit)
   // 2119h WO - VMDATAH - VRAMデータ書き込み (上位8bit)
   vmdata: Vec<u16>,
+  // 2139h/213Ah の読み込みバッファ
+  vram_read_buffer: u16,
   // 213Ch RO - OPHCT   - Hカウンタ
   ophct: u16,
   ophct_low: bool,
@@ -55,6 +121,9 @@ pub struct PPU {
   opvct_low: bool,
   stat77: u8, // 213Eh RO - STAT77  - PPU1ステータス
   stat78: u8, // 213Fh RO - STAT78  - PPU2ステータス
+  // オープンバス: PPU1 (2134h-2136h, 2138h-213Ah, 213Eh) と PPU2 (213Bh-213Dh, 213Fh) が最後に読まれた値を別々に持つ
+  ppu1_mdr: u8,
+  ppu2_mdr: u8,
 
   // 4200h WO - NMITIMEN- 割り込み有効化レジスタ
   nmitimen: u8,
@@ -83,10 +152,14 @@ pub struct PPU {
 
   pub h_counter: u16,
   pub v_counter: u16,
+  // 最後に描いたライン
+  drawn_line: Option<u16>,
 
   pub hblank_flag: bool,
   pub vblank_flag: bool,
   auto_joypad_flag: bool,
+  // 自動ジョイパッド読み込みの開始要求 (Bus が処理する)
+  pub auto_joypad_request: bool,
 
   pub hirq_flag: bool,
   pub virq_flag: bool,
@@ -94,6 +167,87 @@ pub struct PPU {
   virq_wait_flag: bool,
 }
 
+// 8bpp のダイレクトカラー
+// 色番号 BBGGGRRR とパレット番号 bgr から BGR555 を作る
+fn direct_color(index: u8, palette: u8) -> u16 {
+  let r = ((index & 0x07) << 2) | ((palette & 0x01) << 1);
+  let g = (((index >> 3) & 0x07) << 2) | (palette & 0x02);
+  let b = (((index >> 6) & 0x03) << 3) | (palette & 0x04);
+  ((b as u16) << 10) | ((g as u16) << 5) | r as u16
+}
+
+// ライン描画の途中結果
+#[derive(Debug, Clone, Copy)]
+struct LinePixel {
+  color: u16, // BGR555
+  priority: u8,
+  // カラー演算の対象になるか (スプライトはパレット 4-7 だけ)
+  math: bool,
+}
+
+// BGモードごとの BG1-4 の色深度 (0=なし)
+const BG_BPP: [[u8; 4]; 8] = [
+  [2, 2, 2, 2], // Mode 0
+  [4, 4, 2, 0], // Mode 1
+  [4, 4, 0, 0], // Mode 2 (BG3 のタイルマップはオフセットパータイル、offset_per_tile)
+  [8, 4, 0, 0], // Mode 3
+  [8, 2, 0, 0], // Mode 4 (BG3 のタイルマップはオフセットパータイル、offset_per_tile)
+  [4, 2, 0, 0], // Mode 5 (高解像度)
+  [4, 0, 0, 0], // Mode 6 (高解像度、BG3 のタイルマップはオフセットパータイル、offset_per_tile)
+  [8, 7, 0, 0], // Mode 7 (render_mode7_line)
+];
+
+// レイヤ番号 0-3: BG1-4、4: スプライト、5: バックドロップ
+const OBJ: usize = 4;
+const BACKDROP: usize = 5;
+// ウィンドウの番号 0-4 はレイヤと同じ、5: 色ウィンドウ
+const COLOR_WINDOW: usize = 5;
+
+// BGモードごとの重なり順 (手前から)。(レイヤ, 優先度)
+const LAYER_PRIORITY: [&[(usize, u8)]; 8] = [
+  &[(OBJ, 3), (0, 1), (1, 1), (OBJ, 2), (0, 0), (1, 0), (OBJ, 1), (2, 1), (3, 1), (OBJ, 0), (2, 0), (3, 0)],
+  &[(OBJ, 3), (0, 1), (1, 1), (OBJ, 2), (0, 0), (1, 0), (OBJ, 1), (2, 1), (OBJ, 0), (2, 0)],
+  &[(OBJ, 3), (0, 1), (OBJ, 2), (1, 1), (OBJ, 1), (0, 0), (OBJ, 0), (1, 0)],
+  &[(OBJ, 3), (0, 1), (OBJ, 2), (1, 1), (OBJ, 1), (0, 0), (OBJ, 0), (1, 0)],
+  &[(OBJ, 3), (0, 1), (OBJ, 2), (1, 1), (OBJ, 1), (0, 0), (OBJ, 0), (1, 0)],
+  &[(OBJ, 3), (0, 1), (OBJ, 2), (1, 1), (OBJ, 1), (0, 0), (OBJ, 0), (1, 0)],
+  &[(OBJ, 3), (0, 1), (OBJ, 2), (OBJ, 1), (0, 0), (OBJ, 0)],
+  &[(OBJ, 3), (OBJ, 2), (OBJ, 1), (0, 0), (OBJ, 0)],
+];
+const LAYER_PRIORITY_MODE1_BG3: &[(usize, u8)] =
+  &[(2, 1), (OBJ, 3), (0, 1), (1, 1), (OBJ, 2), (0, 0), (1, 0), (OBJ, 1), (OBJ, 0), (2, 0)];
+const LAYER_PRIORITY_MODE7_EXTBG: &[(usize, u8)] =
+  &[(OBJ, 3), (OBJ, 2), (1, 1), (OBJ, 1), (0, 0), (OBJ, 0), (1, 0)];
+
+// 手前から順に見て、最初に見つかった不透明なドット
+// enable: TM/TS、window_disable: TMW/TSW (ウィンドウの内側で表示しない)
+fn front_pixel(
+  order: &[(usize, u8)],
+  layers: &[Vec<Option<LinePixel>>; 5],
+  windows: &[Vec<bool>],
+  enable: u8,
+  window_disable: u8,
+  x: usize,
+) -> Option<(usize, LinePixel)> {
+  order.iter().find_map(|&(layer, priority)| {
+    if enable & (0x01 << layer) == 0 || (window_disable & (0x01 << layer) != 0 && windows[layer][x]) {
+      return None
+    }
+    layers[layer].get(x).copied().flatten().filter(|pixel| pixel.priority == priority).map(|pixel| (layer, pixel))
+  })
+}
+
+// OAM の 1 スプライト分
+#[derive(Debug, Clone, Copy)]
+struct Obj {
+  x: i16,
+  y: u8,
+  tile: u16, // 9bit (bit8 は 2つ目のテーブル)
+  attr: u8, // vhoopppN
+  width: usize,
+  height: usize,
+}
+
 const WINDOW_WIDTH: usize = 256;
 const WINDOW_HEIGHT: usize = 256; // 224
 
@@ -101,18 +255,39 @@ impl PPU {
   pub fn new() -> Self {
     Self {
       cycles: 0,
+      video_standard: VideoStandard::Ntsc,
 
       inidisp: 0x80,
       obsel: 0x00,
       oamaddl: 0x00,
       oamaddh: 0x00,
-      oamdata: 0x00,
+      oam: vec![0; 544],
+      oam_addr: 0,
+      oam_latch: 0,
       bgmode: 0x0F,
       mosaic: 0,
+      mosaic_vcounter: 0,
+      mosaic_start_line: 0,
       bg1sc: 0,
+      bg2sc: 0,
+      bg3sc: 0,
+      bg4sc: 0,
       bg12nba: 0,
-      bg1hofs: 0,
-      bg1vofs: 0,
+      bg34nba: 0,
+      bg_hofs: [0; 4],
+      bg_vofs: [0; 4],
+      bg_ofs_latch: 0,
+      m7hofs: 0,
+      m7vofs: 0,
+      m7_latch: 0,
+      m7sel: 0,
+      m7a: 0,
+      m7b: 0,
+      m7c: 0,
+      m7d: 0,
+      m7x: 0,
+      m7y: 0,
+      mpy: 0,
       vmain: 0x0F,
       cgadd: 0,
       cg_write_low: true,
@@ -120,17 +295,31 @@ impl PPU {
       tm: 0,
       ts: 0,
       tmw: 0,
+      tsw: 0,
+      w12sel: 0,
+      w34sel: 0,
+      wobjsel: 0,
+      wh0: 0,
+      wh1: 0,
+      wh2: 0,
+      wh3: 0,
+      wbglog: 0,
+      wobjlog: 0,
+      coldata: 0,
       cgwsel: 0,
       cgadsub: 0,
       setini: 0,
       vmadd: 0,
       vmdata: vec![0; 32 * 1024], // 32K Word
+      vram_read_buffer: 0,
       ophct: 0x01FF,
       ophct_low: true,
       opvct: 0x01FF,
       opvct_low: true,
       stat77: 0x00,
       stat78: 0x00,
+      ppu1_mdr: 0x00,
+      ppu2_mdr: 0x00,
 
       nmitimen: 0x00,
       wrio: 0xFF,
@@ -146,10 +335,12 @@ impl PPU {
 
       h_counter: 0,
       v_counter: 0,
+      drawn_line: None,
 
       hblank_flag: false,
       vblank_flag: false,
       auto_joypad_flag: false,
+      auto_joypad_request: false,
 
       hirq_flag: false,
       virq_flag: false,
@@ -158,33 +349,63 @@ impl PPU {
     }
   }
 
+  pub fn set_video_standard(&mut self, video_standard: VideoStandard) {
+    self.video_standard = video_standard;
+    // STAT78 bit4: 0=NTSC, 1=PAL
+    match video_standard {
+      VideoStandard::Ntsc => self.stat78 &= !0x10,
+      VideoStandard::Pal => self.stat78 |= 0x10,
+    }
+  }
+
+  // NTSC: 262ライン (0-261), PAL: 312ライン (0-311)
+  fn lines_per_frame(&self) -> u16 {
+    match self.video_standard {
+      VideoStandard::Ntsc => 262,
+      VideoStandard::Pal => 312,
+    }
+  }
+
+  // SETINI bit2 (オーバースキャン) がセットされていれば 239ライン表示で、V-Blank は 240 から
+  pub fn vblank_start_line(&self) -> u16 {
+    if self.setini & 0x04 != 0 { 240 } else { 225 }
+  }
+
   // 256x224px
   // 3.58MHz (21.477MHz) (1.79MHz /12、2.68MHz /8、3.58MHz /6)
   // X = 3.58MHz / 224line / 60FPS = 1ライン分の時間
   // X = 1364 / 6 = 227.33(3.58MHz換算)
-  // MAX: 262スキャンライン
+  // MAX: 262スキャンライン (PAL: 312スキャンライン)
+  // 1ラインのサイクル数(1364)は NTSC/PAL 共通
   // スキャンライン$E1(225: NTSC)または$F0(PAL: 240)からフレームの終わりまで実行されます。
   // 各スキャンラインの開始から約536サイクル後から40サイクルの間一時停止します。
   // 1スキャンラインあたり常に340ドット（ピクセル）??
   // 22 ～ 277 が画面に表示される。
-  // Vカウンタは、NTSC モードでは 0 ～ 261
+  // Vカウンタは、NTSC モードでは 0 ～ 261、PAL モードでは 0 ～ 311
   //   1 ～ 224 の範囲が画面に表示される。
   pub fn tick(&mut self, cycles: u32) {
     self.cycles += cycles;
 
     let line_par_cycles = 1364;
-    self.h_counter = (self.cycles / 4) as u16;
 
     if self.cycles > line_par_cycles {
       self.cycles -= line_par_cycles;
       self.v_counter += 1;
       self.hirq_wait_flag = true;
+      if self.v_counter == self.vblank_start_line() {
+        self.start_vblank();
+      }
     }
+    self.h_counter = (self.cycles / 4) as u16;
 
     self.hvirq();
 
+    let vblank_start = self.vblank_start_line();
+
     // FIXME 最終的には、draw_pixel()を作って、1ピクセルづつ書くようにする。
-    if self.h_counter > 277 && self.v_counter <= 224 {
+    // 1ラインに1回、そのラインの HDMA (H=1104 => 276) より前に描く
+    if self.h_counter >= 276 && self.v_counter < vblank_start && self.drawn_line != Some(self.v_counter) {
+      self.drawn_line = Some(self.v_counter);
       self.draw_line(self.v_counter);
     }
 
@@ -198,13 +419,10 @@ impl PPU {
       self.hblank_flag = true;
     }
 
-    if self.v_counter > 224 {
-      self.set_nmi();
-      self.frame_updated = true;
-    }
-
-    if self.v_counter > 261 {
+    if self.v_counter >= self.lines_per_frame() {
       self.v_counter = 0;
+      // STAT77 のスプライトあふれフラグは V-Blank の終わりにクリアされる
+      self.stat77 &= !0xC0;
       self.virq_wait_flag = true;
       self.clear_nmi();
     }
@@ -213,17 +431,16 @@ impl PPU {
     // V-Blankの外ではクリアされる。
     // セットされるタイミングは、Vカウンタが 0xE1(225) かつ Hカウンタが 0x16 ～ 0x17 (22 ～ 23) の時で、
     // クリアされるタイミングは、Vカウンタが 0 かつ Hカウンタが 0x1E (30) の時。
-    if !self.vblank_flag && self.v_counter >= 255 && self.h_counter >= 22 {
+    if !self.vblank_flag && self.v_counter >= vblank_start && self.h_counter >= 22 {
       self.vblank_flag = true;
-      self.auto_joypad_flag = true;
     }
-    if self.vblank_flag && self.v_counter >= 0 && self.h_counter >= 30 {
+    if self.vblank_flag && self.v_counter == 0 && self.h_counter >= 30 {
       self.vblank_flag = false;
     }
 
     // 自動ジョイパッドステータス 自動ジョイパッド読み込み時にセットされる。 完了時にクリアされる。
     // 典型的に、これは V-Blank 開始時にセットされ、 3 スキャンライン後に完了する。
-    if self.auto_joypad_flag && self.v_counter >= 255 + 3 {
+    if self.auto_joypad_flag && self.v_counter >= vblank_start + 3 {
       self.auto_joypad_flag = false;
     }
   }
@@ -259,90 +476,573 @@ impl PPU {
     }
   }
 
+  // V-Blank の最初のラインに入った時に 1 回だけ行う
+  fn start_vblank(&mut self) {
+    self.set_nmi();
+    self.frame_updated = true;
+    // 強制ブランク中でなければ、V-Blank の開始時に OAM アドレスが OAMADD に戻る
+    if self.inidisp & 0x80 == 0 {
+      self.oam_addr = self.oam_reload_addr();
+    }
+    // NMITIMEN bit0 が有効な時だけ自動読み込みが行われる
+    if self.nmitimen & 0x01 != 0 {
+      self.auto_joypad_flag = true;
+      self.auto_joypad_request = true;
+    }
+  }
+
   fn set_nmi(&mut self) {
     self.rdnmi = self.rdnmi | 0x80;
   }
 
+  // RDNMI のフラグだけ落とす。HVBJOY の V-Blank フラグはそのまま
   fn clear_nmi(&mut self) {
     self.rdnmi = self.rdnmi & 0x0F;
-    self.vblank_flag = false;
   }
 
-  fn bg1_tilemaps(&mut self, y: u32) -> &[u16] {
-    let base = (((self.bg1sc & 0xFC) as u32) << 8) as usize;
-    let offset: usize = y as usize / 8;
-    let base = base + 32 * offset;
-    &self.vmdata[base..=base + 32]
+  // BGnSC
+  // 7-2 タイルマップのベースアドレス (1Kワード単位)
+  // 1-0 タイルマップのサイズ (0=32x32, 1=64x32, 2=32x64, 3=64x64)
+  fn bg_sc(&self, bg: usize) -> u8 {
+    match bg {
+      0 => self.bg1sc,
+      1 => self.bg2sc,
+      2 => self.bg3sc,
+      _ => self.bg4sc,
+    }
+  }
+
+  // タイルデータのベースアドレス (ワード)。BG12NBA/BG34NBA の 4bit ずつ (4Kワード単位)
+  fn bg_char_base(&self, bg: usize) -> usize {
+    let nba = if bg < 2 { self.bg12nba } else { self.bg34nba };
+    let nibble = if bg.is_multiple_of(2) { nba & 0x0F } else { nba >> 4 };
+    (nibble as usize) << 12
+  }
+
+  // スクロール位置 (H, V)
+  fn bg_scroll(&self, bg: usize) -> (u16, u16) {
+    (self.bg_hofs[bg], self.bg_vofs[bg])
+  }
+
+  // Mode 7 のレジスタ (M7HOFS/M7VOFS, M7A-M7D, M7X/M7Y) は 2回書き込みで 16bit
+  fn write_m7_register(&mut self, data: u8) -> u16 {
+    let value = ((data as u16) << 8) | self.m7_latch as u16;
+    self.m7_latch = data;
+    value
+  }
+
+  // M7A (16bit 符号付き) x M7B の上位8bit (8bit 符号付き) = 24bit 符号付き
+  fn update_mpy(&mut self) {
+    self.mpy = (self.m7a as i16 as i32) * ((self.m7b >> 8) as i8 as i32);
+  }
+
+  // Mode 7 の 1 ライン分
+  // BG1: 8bpp (256色)
+  // BG2: EXTBG (SETINI bit6) の時だけ。同じデータの bit7 を優先度、bit0-6 を色に使う
+  fn render_mode7_line(&self, bg: usize, line: u16) -> Vec<Option<LinePixel>> {
+    let mut pixels = vec![None; WINDOW_WIDTH];
+    if bg == 1 && self.setini & 0x40 == 0 {
+      return pixels
+    }
+
+    // 13bit 符号付き
+    let sign13 = |v: u16| ((v << 3) as i16 >> 3) as i32;
+    // スクロール値と中心の差は 10bit に切り詰める
+    let clip = |v: i32| if v & 0x2000 != 0 { v | !0x03FF } else { v & 0x03FF };
+    let a = self.m7a as i16 as i32;
+    let b = self.m7b as i16 as i32;
+    let c = self.m7c as i16 as i32;
+    let d = self.m7d as i16 as i32;
+    let hofs = sign13(self.m7hofs);
+    let vofs = sign13(self.m7vofs);
+    let cx = sign13(self.m7x);
+    let cy = sign13(self.m7y);
+
+    let y = if self.m7sel & 0x02 != 0 { 255 - (line as i32 & 0xFF) } else { line as i32 & 0xFF };
+    // 行列の計算は下位6bitを落として行う
+    let origin_x = ((a * clip(hofs - cx)) & !63) + ((b * clip(vofs - cy)) & !63) + ((b * y) & !63) + (cx << 8);
+    let origin_y = ((c * clip(hofs - cx)) & !63) + ((d * clip(vofs - cy)) & !63) + ((d * y) & !63) + (cy << 8);
+
+    let screen_over = self.m7sel >> 6;
+    for (screen_x, pixel) in pixels.iter_mut().enumerate() {
+      let x = if self.m7sel & 0x01 != 0 { 255 - screen_x as i32 } else { screen_x as i32 };
+      let px = (origin_x + a * x) >> 8;
+      let py = (origin_y + c * x) >> 8;
+
+      let outside = !(0..1024).contains(&px) || !(0..1024).contains(&py);
+      let tile = match screen_over {
+        2 if outside => continue,
+        3 if outside => 0,
+        _ => {
+          // 128x128 タイルのマップ。各ワードの下位バイトがタイル番号
+          let (px, py) = (px & 0x03FF, py & 0x03FF);
+          self.vmdata[((py >> 3) * 128 + (px >> 3)) as usize] & 0x00FF
+        }
+      };
+      // タイルは 8x8 の 8bpp で、各ワードの上位バイトがドットの色番号
+      let index = (self.vmdata[(tile as usize * 64) + ((py & 0x07) * 8 + (px & 0x07)) as usize] >> 8) as u8;
+
+      *pixel = if bg == 0 {
+        if index == 0 {
+          continue;
+        }
+        let color = if self.cgwsel & 0x01 != 0 { direct_color(index, 0) } else { self.cgdata[index as usize] };
+        Some(LinePixel { color, priority: 0, math: true })
+      } else {
+        if index & 0x7F == 0 {
+          continue;
+        }
+        Some(LinePixel { color: self.cgdata[(index & 0x7F) as usize], priority: index >> 7, math: true })
+      };
+    }
+    pixels
+  }
+
+  // BGMODE bit4-7: BG1-4 のタイルサイズ (0=8x8, 1=16x16)
+  // Mode 5/6 は横 512 ドットなので、タイルの横幅は常に 16
+  fn bg_tile_size(&self, bg: usize) -> (usize, usize) {
+    let mode = self.bgmode & 0x07;
+    let large = self.bgmode & (0x10 << bg) != 0;
+    let hires = mode == 5 || mode == 6;
+    let tile_width = if large || hires { 16 } else { 8 };
+    let tile_height = if large { 16 } else { 8 };
+    (tile_width, tile_height)
   }
 
-  fn bg1tile(&mut self, tileindex: u16) -> &[u16] {
-    // bgモードみる
-    //  -> いまは2bpp固定
-    let tilesize: usize = 2 /*bpp*/ * 8 /* 8x8mode */ / 2 /*byte to word */;
-    let base = (((self.bg12nba & 0x0F) as u32) << 12) as usize;
-    let addr = base + tilesize * tileindex as usize;
-    let data = &self.vmdata[addr..=(addr + tilesize)];
-    data
+  // BG 上の座標 (x, y) にあるタイルマップのエントリ (vhopppcc cccccccc)
+  fn bg_map_entry(&self, bg: usize, x: usize, y: usize) -> u16 {
+    let (tile_width, tile_height) = self.bg_tile_size(bg);
+    let sc = self.bg_sc(bg);
+    let map_base = ((sc & 0xFC) as usize) << 8;
+    let map_width = if sc & 0x01 != 0 { 64 } else { 32 };
+    let map_height = if sc & 0x02 != 0 { 64 } else { 32 };
+
+    // タイルマップ: 32x32 の画面が 左上, 右上, 左下, 右下 の順に並ぶ
+    let tx = (x / tile_width) % map_width;
+    let ty = (y / tile_height) % map_height;
+    let mut map_addr = map_base + (ty % 32) * 32 + (tx % 32);
+    if tx >= 32 {
+      map_addr += 0x400;
+    }
+    if ty >= 32 {
+      map_addr += if map_width == 64 { 0x800 } else { 0x400 };
+    }
+    self.vmdata[map_addr & 0x7FFF]
   }
 
-  fn palette(&mut self, palette_size: u16) -> Vec<[u8; 3]> {
-    // 2bpp固定
-    let base =  palette_size as usize;
-    let palette_size = 4; // 2bpp
-    let data = &self.cgdata[base..=base + palette_size];
-    let mut res: Vec<[u8; 3]> = vec![];
-    for v in data {
-      res.push(bgr555_to_rgb888(*v));
+  // オフセットパータイル (Mode 2/4/6 の BG1/BG2)
+  // 画面の 2 列目以降のタイルは、BG3 のタイルマップの 1 行目 (H) と 2 行目 (V) の値でスクロール位置を置き換える。
+  // Mode 4 は 1 行だけで、bit15 が 0 なら H、1 なら V。bit13/14 がそれぞれ BG1/BG2 に適用するかどうか
+  fn offset_per_tile(&self, bg: usize, mode: usize, screen_x: usize, hofs: u16, vofs: u16) -> (u16, u16) {
+    let column = (screen_x + (hofs & 0x07) as usize) / 8;
+    if column == 0 {
+      return (hofs, vofs)
     }
-    res
+    let valid = 0x2000 << bg;
+    let (bg3_hofs, bg3_vofs) = self.bg_scroll(2);
+    let x = (column - 1) * 8 + (bg3_hofs & !0x07) as usize;
+    let y = bg3_vofs as usize;
+    let hlookup = self.bg_map_entry(2, x, y);
+    let (mut hofs, mut vofs) = (hofs, vofs);
+    if mode == 4 {
+      if hlookup & valid != 0 {
+        if hlookup & 0x8000 == 0 {
+          hofs = (hofs & 0x07) | (hlookup & 0x03F8);
+        } else {
+          vofs = hlookup & 0x03FF;
+        }
+      }
+    } else {
+      let vlookup = self.bg_map_entry(2, x, y + 8);
+      if hlookup & valid != 0 {
+        hofs = (hofs & 0x07) | (hlookup & 0x03F8);
+      }
+      if vlookup & valid != 0 {
+        vofs = vlookup & 0x03FF;
+      }
+    }
+    (hofs, vofs)
   }
 
-  fn draw_line(&mut self, scanline: u16) {
-    // TODO
-    // BG1HOFS = x offset
-    // BG1VOFS = y offset
-    let offset_x: u32 = 0;
-    let offset_y: u32 = 0;
+  // BG の 1 ライン分の色を求める。透明なドットは None
+  fn render_bg_line(&self, bg: usize, line: u16) -> Vec<Option<LinePixel>> {
+    let mode = (self.bgmode & 0x07) as usize;
+    if mode == 7 {
+      return self.render_mode7_line(bg, line)
+    }
+    let mut pixels = vec![None; WINDOW_WIDTH];
+    let bpp = BG_BPP[mode][bg] as usize;
+    if bpp == 0 {
+      return pixels
+    }
 
-    let ox = offset_x;
-    let oy = offset_y + scanline as u32;
+    let hires = mode == 5 || mode == 6;
+    let (tile_width, tile_height) = self.bg_tile_size(bg);
+    let char_base = self.bg_char_base(bg);
+    let (hofs, vofs) = self.bg_scroll(bg);
+    // 高解像度モードの BGnHOFS は 512 ドット単位の座標で 2 ドットずつ動く
+    let scale = if hires { 2 } else { 1 };
+    let offset_per_tile = matches!(mode, 2 | 4 | 6) && bg < 2;
 
-    let tilemaps = self.bg1_tilemaps(oy).to_vec();
-    let tile_y: usize = oy as usize % 8;
+    for (screen_x, pixel) in pixels.iter_mut().enumerate() {
+      let (hofs, vofs) = if offset_per_tile {
+        self.offset_per_tile(bg, mode, screen_x, hofs, vofs)
+      } else {
+        (hofs, vofs)
+      };
+      // 高解像度モードは偶数ドットだけ表示する
+      let x = screen_x * scale + hofs as usize * scale;
+      let y = vofs as usize + line as usize;
+      // vhopppcc cccccccc
+      let entry = self.bg_map_entry(bg, x, y);
+      let tile_number = (entry & 0x03FF) as usize;
+      let palette = ((entry & 0x1C00) >> 10) as usize;
+      let priority = ((entry & 0x2000) >> 13) as u8;
 
-    let mut draw_x: usize = 0;
-    for (i, tilemap) in tilemaps.iter().enumerate() {
-      if (i as usize * 8) < ox as usize {
-        continue;
+      let mut px = x % tile_width;
+      let mut py = y % tile_height;
+      if entry & 0x4000 != 0 {
+        px = tile_width - 1 - px;
+      }
+      if entry & 0x8000 != 0 {
+        py = tile_height - 1 - py;
       }
-      if i as usize * 8 + ox as usize > WINDOW_WIDTH {
+      // 16x16 タイルは 右 = +1、下 = +16 のタイルを使う
+      let tile_number = (tile_number + px / 8 + (py / 8) * 16) & 0x03FF;
+
+      let index = self.tile_pixel(char_base, tile_number, bpp, px % 8, py % 8);
+      if index == 0 {
         continue;
       }
+      let color = match bpp {
+        // Mode 0 は BG ごとに 32色ずつ別のパレット
+        2 => self.cgdata[(if mode == 0 { bg * 32 } else { 0 }) + palette * 4 + index as usize],
+        4 => self.cgdata[palette * 16 + index as usize],
+        _ => {
+          // CGWSEL bit0: ダイレクトカラー
+          if self.cgwsel & 0x01 != 0 {
+            direct_color(index, palette as u8)
+          } else {
+            self.cgdata[index as usize]
+          }
+        }
+      };
+      *pixel = Some(LinePixel { color, priority, math: true });
+    }
+    pixels
+  }
+
+  // タイル内の 1 ドットの色番号
+  // 2枚のビットプレーンが 1 ワード (下位=偶数プレーン、上位=奇数プレーン) に入っていて、8ワードごとに次の 2 枚が続く
+  fn tile_pixel(&self, char_base: usize, tile_number: usize, bpp: usize, x: usize, y: usize) -> u8 {
+    let addr = char_base + tile_number * bpp * 4 + y;
+    let bit = 7 - x;
+    let mut index = 0;
+    for plane in 0..bpp / 2 {
+      let word = self.vmdata[(addr + plane * 8) & 0x7FFF];
+      index |= (((word >> bit) & 0x01) as u8) << (plane * 2);
+      index |= (((word >> (bit + 8)) & 0x01) as u8) << (plane * 2 + 1);
+    }
+    index
+  }
+
+  fn mosaic_size(&self) -> u16 {
+    ((self.mosaic >> 4) + 1) as u16
+  }
+
+  fn mosaic_scanline(&mut self, scanline: u16) {
+    // ライン0 は表示されないが、前のフレームのブロックを引きずらないようにここでもリセットする
+    if scanline <= 1 {
+      self.mosaic_vcounter = 0;
+    }
+    self.mosaic_vcounter = self.mosaic_vcounter.saturating_sub(1);
+    if self.mosaic_vcounter == 0 {
+      self.mosaic_start_line = scanline;
+      self.mosaic_vcounter = self.mosaic_size();
+    }
+  }
+
+  // モザイクのかかった BG は、ブロックの先頭のラインを繰り返す
+  fn mosaic_line(&self, bg: usize, scanline: u16) -> u16 {
+    if self.mosaic & (0x01 << bg) != 0 {
+      self.mosaic_start_line
+    } else {
+      scanline
+    }
+  }
+
+  // モザイクのかかった BG は、ブロックの先頭のライン/ドットを繰り返す
+  fn render_bg_line_with_mosaic(&self, bg: usize, scanline: u16) -> Vec<Option<LinePixel>> {
+    let size = self.mosaic_size();
+    let line = self.mosaic_line(bg, scanline);
+    let mut pixels = self.render_bg_line(bg, line);
+    // Mode 7 の EXTBG (BG2) の横方向は BG1 の設定に従う
+    let horizontal = if self.bgmode & 0x07 == 7 { 0 } else { bg };
+    if self.mosaic & (0x01 << horizontal) != 0 {
+      let size = size as usize;
+      for x in 0..WINDOW_WIDTH {
+        pixels[x] = pixels[x - x % size];
+      }
+    }
+    pixels
+  }
+
+  // VRAM/OAM は V-Blank 中か強制ブランク中しか書き込めない
+  fn vram_accessible(&self) -> bool {
+    self.inidisp & 0x80 != 0 || self.v_counter >= self.vblank_start_line()
+  }
+
+  fn draw_line(&mut self, scanline: u16) {
+    self.mosaic_scanline(scanline);
+
+    // INIDISP bit7: 強制ブランク
+    if self.inidisp & 0x80 != 0 {
+      let base_index = scanline as usize * WINDOW_WIDTH * 3;
+      self.screen_state[base_index..base_index + WINDOW_WIDTH * 3].fill(0);
+      return
+    }
+
+    let mode = (self.bgmode & 0x07) as usize;
+    let mut layers: [Vec<Option<LinePixel>>; 5] = Default::default();
+    for (bg, layer) in layers.iter_mut().enumerate().take(4) {
+      // TM/TS: メイン/サブ画面に表示するレイヤ
+      if (self.tm | self.ts) & (0x01 << bg) != 0 {
+        *layer = self.render_bg_line_with_mosaic(bg, scanline);
+      }
+    }
+    // スプライトの数/タイル数の判定は表示しなくても行われる
+    let obj = self.render_obj_line(scanline);
+    if (self.tm | self.ts) & 0x10 != 0 {
+      layers[OBJ] = obj;
+    }
+    let windows: Vec<Vec<bool>> = (0..6).map(|layer| self.window_line(layer)).collect();
+
+    // BGMODE bit3: Mode 1 の BG3 優先
+    let order = match mode {
+      1 if self.bgmode & 0x08 != 0 => LAYER_PRIORITY_MODE1_BG3,
+      // SETINI bit6: EXTBG
+      7 if self.setini & 0x40 != 0 => LAYER_PRIORITY_MODE7_EXTBG,
+      _ => LAYER_PRIORITY[mode],
+    };
+    for x in 0..WINDOW_WIDTH {
+      // メイン画面にドットがなければバックドロップ (CGRAM 0番)
+      let (main_layer, main) = front_pixel(order, &layers, &windows, self.tm, self.tmw, x)
+        .unwrap_or((BACKDROP, LinePixel { color: self.cgdata[0], priority: 0, math: true }));
+      let sub = front_pixel(order, &layers, &windows, self.ts, self.tsw, x);
+      let color = self.color_math(main_layer, main, sub, windows[COLOR_WINDOW][x]);
+
+      // INIDISP bit3-0: 明るさ (0=黒, 1-15=(N+1)/16 倍)
+      let brightness = match self.inidisp & 0x0F {
+        0 => 0,
+        n => n as u16 + 1,
+      };
+      let rgb = bgr555_to_rgb888(color).map(|c| (c as u16 * brightness / 16) as u8);
+      let base_index = (scanline as usize * WINDOW_WIDTH + x) * 3;
+      self.screen_state[base_index..base_index + 3].copy_from_slice(&rgb);
+    }
+  }
+
+  // レイヤ (0-3: BG1-4, 4: OBJ, 5: 色ウィンドウ) の 1 ライン分のウィンドウの内側
+  fn window_line(&self, layer: usize) -> Vec<bool> {
+    let (sel, logic) = match layer {
+      0 => (self.w12sel & 0x0F, self.wbglog & 0x03),
+      1 => (self.w12sel >> 4, (self.wbglog >> 2) & 0x03),
+      2 => (self.w34sel & 0x0F, (self.wbglog >> 4) & 0x03),
+      3 => (self.w34sel >> 4, (self.wbglog >> 6) & 0x03),
+      OBJ => (self.wobjsel & 0x0F, self.wobjlog & 0x03),
+      _ => (self.wobjsel >> 4, (self.wobjlog >> 2) & 0x03),
+    };
+    (0..WINDOW_WIDTH)
+      .map(|x| {
+        let x = x as u8;
+        // 左端 > 右端 なら範囲なし
+        let w1 = (self.wh0 <= x && x <= self.wh1) != (sel & 0x01 != 0);
+        let w2 = (self.wh2 <= x && x <= self.wh3) != (sel & 0x04 != 0);
+        match (sel & 0x02 != 0, sel & 0x08 != 0) {
+          (false, false) => false,
+          (true, false) => w1,
+          (false, true) => w2,
+          (true, true) => match logic {
+            0 => w1 || w2,
+            1 => w1 && w2,
+            2 => w1 != w2,
+            _ => w1 == w2,
+          },
+        }
+      })
+      .collect()
+  }
 
-      let tileindex = tilemap & 0x02FF;
-      let palette_select = (tilemap & 0x1C00) >> 10;
+  // CGWSEL bit7-6: メイン画面を黒にする、bit5-4: カラー演算をしない
+  // (0=しない, 1=色ウィンドウの外, 2=色ウィンドウの中, 3=常に)
+  fn color_math(&self, main_layer: usize, main: LinePixel, sub: Option<(usize, LinePixel)>, in_color_window: bool) -> u16 {
+    let region = |mode: u8| match mode {
+      0 => false,
+      1 => !in_color_window,
+      2 => in_color_window,
+      _ => true,
+    };
+    let clip = region(self.cgwsel >> 6);
+    let prevent = region((self.cgwsel >> 4) & 0x03);
+    let main_color = if clip { 0 } else { main.color };
 
-      let tile = self.bg1tile(tileindex).to_vec();
-      let palette = self.palette(palette_select);
+    // CGADSUB bit0-5: BG1-4, OBJ, バックドロップ のカラー演算を有効にする
+    if prevent || !main.math || self.cgadsub & (0x01 << main_layer) == 0 {
+      return main_color
+    }
+    // CGWSEL bit1: 1=サブ画面と演算 (サブ画面が透明なら固定色)、0=固定色と演算
+    let (operand, transparent) = match sub {
+      Some((_, pixel)) if self.cgwsel & 0x02 != 0 => (pixel.color, false),
+      _ => (self.coldata, self.cgwsel & 0x02 != 0),
+    };
+    // CGADSUB bit7: 減算、bit6: 1/2。メイン画面が黒にされた所とサブ画面が透明な所は 1/2 にしない
+    let subtract = self.cgadsub & 0x80 != 0;
+    let half = self.cgadsub & 0x40 != 0 && !clip && !transparent;
+    let mut color = 0;
+    for shift in [0, 5, 10] {
+      let a = ((main_color >> shift) & 0x1F) as i16;
+      let b = ((operand >> shift) & 0x1F) as i16;
+      let mut v = if subtract { (a - b).max(0) } else { a + b };
+      if half {
+        v >>= 1;
+      }
+      color |= (v.min(0x1F) as u16) << shift;
+    }
+    color
+  }
 
-      let line = tile[tile_y];
-      for x in 0..8 {
+  // OAMADDH/OAMADDL はワードアドレス
+  fn oam_reload_addr(&self) -> u16 {
+    ((((self.oamaddh & 0x01) as u16) << 8) | self.oamaddl as u16) << 1
+  }
 
-        if draw_x > WINDOW_WIDTH {
-          break;
+  fn write_oamdata(&mut self, data: u8) {
+    let addr = self.oam_addr as usize;
+    // 表示中の書き込みは無視される
+    if self.vram_accessible() {
+      if addr < 0x200 {
+        if addr & 0x01 == 0 {
+          self.oam_latch = data;
+        } else {
+          self.oam[addr - 1] = self.oam_latch;
+          self.oam[addr] = data;
         }
+      } else {
+        // 上位テーブルはそのまま書き込まれる (220h-3FFh は 200h-21Fh のミラー)
+        self.oam[0x200 | (addr & 0x1F)] = data;
+      }
+    }
+    self.oam_addr = (self.oam_addr + 1) & 0x03FF;
+  }
 
-        let mask = 0x80 >> x;
-        let palette_index = ((line & (mask << 8)) >> (15 - x)) + ((line & mask) >> (7 - x));
-        let rgb = palette[palette_index as usize];
+  // OBSEL bit7-5: スプライトのサイズ (小, 大)
+  fn obj_size(&self, large: bool) -> (usize, usize) {
+    let sizes = match self.obsel >> 5 {
+      0 => [(8, 8), (16, 16)],
+      1 => [(8, 8), (32, 32)],
+      2 => [(8, 8), (64, 64)],
+      3 => [(16, 16), (32, 32)],
+      4 => [(16, 16), (64, 64)],
+      5 => [(32, 32), (64, 64)],
+      6 => [(16, 32), (32, 64)],
+      _ => [(16, 32), (32, 32)],
+    };
+    sizes[large as usize]
+  }
 
-        let base_index = (scanline as usize * WINDOW_WIDTH + draw_x) * 3;
-        self.screen_state[base_index + 0] = rgb[0];
-        self.screen_state[base_index + 1] = rgb[1];
-        self.screen_state[base_index + 2] = rgb[2];
-        draw_x += 1;
+  fn obj(&self, index: usize) -> Obj {
+    let low = &self.oam[index * 4..index * 4 + 4];
+    let high = (self.oam[0x200 + index / 4] >> ((index % 4) * 2)) & 0x03;
+    let x = low[0] as i16 | ((high as i16 & 0x01) << 8);
+    let (width, height) = self.obj_size(high & 0x02 != 0);
+    Obj {
+      // 9bit 符号付き
+      x: if x >= 256 { x - 512 } else { x },
+      y: low[1],
+      tile: low[2] as u16 | ((low[3] as u16 & 0x01) << 8),
+      attr: low[3],
+      width,
+      height,
+    }
+  }
+
+  // スプライトの 1 ライン分
+  // 1ラインに 32 個まで (超えたら STAT77 bit6)、8x1 ドットのタイルで 34 個まで (超えたら STAT77 bit7)
+  fn render_obj_line(&mut self, line: u16) -> Vec<Option<LinePixel>> {
+    let mut pixels = vec![None; WINDOW_WIDTH];
+    // スプライトは Y の次のラインから表示される
+    let line = line.wrapping_sub(1) & 0xFF;
+
+    // OAMADDH bit7: OAMADDL の指すスプライトから優先度が高い
+    let first = if self.oamaddh & 0x80 != 0 { ((self.oamaddl >> 1) & 0x7F) as usize } else { 0 };
+    let mut in_range: Vec<Obj> = vec![];
+    for i in 0..128 {
+      let obj = self.obj((first + i) % 128);
+      // X=-256 のスプライトは表示されないが、数には入る
+      if obj.x != -256 && (obj.x <= -(obj.width as i16) || obj.x >= 256) {
+        continue;
+      }
+      if ((line as u8).wrapping_sub(obj.y) as usize) >= obj.height {
+        continue;
+      }
+      if in_range.len() == 32 {
+        self.stat77 |= 0x40;
+        break;
+      }
+      in_range.push(obj);
+    }
+
+    // タイルは範囲内の最後のスプライトから読み込まれるので、あふれると優先度の高いスプライトが欠ける
+    let mut slivers: Vec<(Obj, usize)> = vec![];
+    'fetch: for obj in in_range.iter().rev() {
+      for column in 0..obj.width / 8 {
+        let x = obj.x + (column * 8) as i16;
+        if obj.x != -256 && x > -8 && x < 256 {
+          if slivers.len() == 34 {
+            self.stat77 |= 0x80;
+            break 'fetch;
+          }
+          slivers.push((*obj, column));
+        }
       }
     }
+
+    // OBSEL bit2-0: ネームベース (8Kワード単位)、bit4-3: 2つ目のテーブルまでの間隔 (4Kワード単位)
+    let name_base = ((self.obsel & 0x07) as usize) << 13;
+    let name_gap = ((((self.obsel >> 3) & 0x03) + 1) as usize) << 12;
+    // 優先度の高いスプライトが手前になるように、後ろから描いて上書きする
+    for (obj, column) in slivers {
+      let mut y = ((line as u8).wrapping_sub(obj.y)) as usize;
+      if obj.attr & 0x80 != 0 {
+        y = obj.height - 1 - y;
+      }
+      let tile_column = if obj.attr & 0x40 != 0 { obj.width / 8 - 1 - column } else { column };
+      // 16x16 タイルのテーブルの中で、右・下に折り返す
+      let tile = (obj.tile & 0x100)
+        | ((obj.tile + tile_column as u16) & 0x0F)
+        | ((obj.tile + ((y / 8) as u16) * 16) & 0xF0);
+      let base = name_base + if tile & 0x100 != 0 { name_gap } else { 0 };
+      let palette = ((obj.attr >> 1) & 0x07) as usize;
+      let priority = (obj.attr >> 4) & 0x03;
+      for px in 0..8 {
+        let x = obj.x + (column * 8 + px) as i16;
+        if x < 0 || x >= WINDOW_WIDTH as i16 {
+          continue;
+        }
+        let tile_x = if obj.attr & 0x40 != 0 { 7 - px } else { px };
+        let index = self.tile_pixel(base & 0x7FFF, (tile & 0xFF) as usize, 4, tile_x, y % 8);
+        if index == 0 {
+          continue;
+        }
+        // スプライトのパレットは 128-255
+        pixels[x as usize] = Some(LinePixel {
+          color: self.cgdata[128 + palette * 16 + index as usize],
+          priority,
+          math: palette >= 4,
+        });
+      }
+    }
+    pixels
   }
 
   fn increment_timing(&self) -> u8 {
@@ -352,23 +1052,81 @@ impl PPU {
   fn increment_vmadd(&mut self) {
     // 7 上位/下位バイトにアクセスした後、VRAM アドレスをインクリメントします (0=下位、1=上位)
     // 6-4 未使用
-    // TODO アドレス変換はまだ未実装。
-    // 3-2 アドレス変換 (0..3 = 0 ビット/なし、8 ビット、9 ビット、10 ビット)
+    // 3-2 アドレス変換 (0..3 = 0 ビット/なし、8 ビット、9 ビット、10 ビット) => vram_addr()
     // 1-0 アドレスインクリメント ステップ (0..3 = ワード アドレスを 1、32、128、128 ずつインクリメント)
-    // let timing = (self.vmain & 0x80) >> 7;
-    // let address_transfer = (self.vmain & 0x0C) >> 2;
     let step = self.vmain & 0x03;
-    self.vmadd += match step {
+    self.vmadd = self.vmadd.wrapping_add(match step {
       0 => 1,
       1 => 32,
       2 | 3 => 128,
       _ => panic!("invalid address increment step!"),
+    })
+  }
+
+  // アドレス変換を通した VRAM のワードアドレス
+  // 8 ビット : aaaaaaaa YYYxxxxx => aaaaaaaa xxxxxYYY
+  // 9 ビット : aaaaaaaY YYxxxxxx => aaaaaaax xxxxxYYY
+  // 10 ビット: aaaaaaYY Yxxxxxxx => aaaaaaxx xxxxxYYY
+  fn vram_addr(&self) -> usize {
+    let a = self.vmadd;
+    let addr = match (self.vmain & 0x0C) >> 2 {
+      0 => a,
+      1 => (a & 0xFF00) | ((a & 0x001F) << 3) | ((a >> 5) & 0x07),
+      2 => (a & 0xFE00) | ((a & 0x003F) << 3) | ((a >> 6) & 0x07),
+      _ => (a & 0xFC00) | ((a & 0x007F) << 3) | ((a >> 7) & 0x07),
+    };
+    (addr & 0x7FFF) as usize
+  }
+
+  // VMADD を書き換えると、その位置の値が読み込み用のバッファに入る
+  fn prefetch_vram(&mut self) {
+    self.vram_read_buffer = self.vmdata[self.vram_addr()];
+  }
+
+  // 読み込みはバッファの値を返し、インクリメントするタイミングで次の値をバッファに入れる
+  fn read_vmdatal(&mut self) -> u8 {
+    let value = self.vram_read_buffer as u8;
+    if self.increment_timing() == 0 {
+      self.prefetch_vram();
+      self.increment_vmadd();
+    }
+    value
+  }
+
+  fn read_vmdatah(&mut self) -> u8 {
+    let value = (self.vram_read_buffer >> 8) as u8;
+    if self.increment_timing() == 1 {
+      self.prefetch_vram();
+      self.increment_vmadd();
     }
+    value
+  }
+
+  fn read_oamdata(&mut self) -> u8 {
+    let addr = self.oam_addr as usize;
+    let value = if addr < 0x200 { self.oam[addr] } else { self.oam[0x200 | (addr & 0x1F)] };
+    self.oam_addr = (self.oam_addr + 1) & 0x03FF;
+    value
+  }
+
+  // 書き込みと同じく、下位 => 上位の順。上位バイトの bit7 は PPU2 のオープンバス
+  fn read_cgdata(&mut self) -> u8 {
+    let color = self.cgdata[self.cgadd as usize];
+    let value = if self.cg_write_low {
+      color as u8
+    } else {
+      self.cgadd = self.cgadd.wrapping_add(1);
+      ((color >> 8) as u8 & 0x7F) | (self.ppu2_mdr & 0x80)
+    };
+    self.cg_write_low = !self.cg_write_low;
+    value
   }
 
   fn write_vmdatal(&mut self, data: u8) {
-    let vmadd = (self.vmadd & 0x7FFF) as usize;
-    self.vmdata[vmadd] = self.replace_lsb(self.vmdata[vmadd], data);
+    let vmadd = self.vram_addr();
+    if self.vram_accessible() {
+      self.vmdata[vmadd] = self.replace_lsb(self.vmdata[vmadd], data);
+    }
     println!("write_vmdatal({:02X}) addr: {:04X}, data: {:04X}", data, vmadd, self.vmdata[vmadd]);
     if self.increment_timing() == 0 {
       self.increment_vmadd();
@@ -376,8 +1134,10 @@ impl PPU {
   }
 
   fn write_vmdatah(&mut self, data: u8) {
-    let vmadd = (self.vmadd & 0x7FFF) as usize;
-    self.vmdata[vmadd] = self.replace_msb(self.vmdata[vmadd], data);
+    let vmadd = self.vram_addr();
+    if self.vram_accessible() {
+      self.vmdata[vmadd] = self.replace_msb(self.vmdata[vmadd], data);
+    }
     println!("write_vmdatah({:02X}) addr: {:04X}, data: {:04X}", data, vmadd, self.vmdata[vmadd]);
     if self.increment_timing() == 1 {
       self.increment_vmadd();
@@ -392,30 +1152,60 @@ impl PPU {
     (data & 0x00FF) | ((value as u16) << 8)
   }
 
-  pub fn write(&mut self, addr: u16, data: u8) {
+  // 未接続のアドレスなら None
+  pub fn write(&mut self, addr: u16, data: u8) -> Option<()> {
     match addr {
       0x2100 => self.inidisp = data,
       0x2101 => self.obsel = data,
-      0x2102 => self.oamaddl = data,
-      0x2103 => self.oamaddh = data,
-      0x2104 => self.oamdata = data,
+      0x2102 => {
+        self.oamaddl = data;
+        self.oam_addr = self.oam_reload_addr();
+      }
+      0x2103 => {
+        self.oamaddh = data;
+        self.oam_addr = self.oam_reload_addr();
+      }
+      0x2104 => self.write_oamdata(data),
       0x2105 => {
         println!("BGMODE: {:02X}", data);
         self.bgmode = data
       },
       0x2106 => self.mosaic = data,
       0x2107 => self.bg1sc = data,
-      0x2108..=0x210A => {} // FIXME BG画面設定
+      0x2108 => self.bg2sc = data,
+      0x2109 => self.bg3sc = data,
+      0x210A => self.bg4sc = data,
       0x210B => self.bg12nba = data, // 04 => BG1 4 x 0x2000 ?
-      0x210C => {}, // FIXME BG3,4タイルデータアドレス
-      0x210D => {
-        println!("BG1HOFS: {:02X}", data);
-        self.bg1hofs = data
+      0x210C => self.bg34nba = data,
+      0x210D..=0x2114 => {
+        let bg = ((addr - 0x210D) / 2) as usize;
+        if addr == 0x210D {
+          self.m7hofs = self.write_m7_register(data) & 0x1FFF;
+        }
+        if addr == 0x210E {
+          self.m7vofs = self.write_m7_register(data) & 0x1FFF;
+        }
+        if (addr - 0x210D).is_multiple_of(2) {
+          self.bg_hofs[bg] = (((data as u16) << 8) | (self.bg_ofs_latch & !0x07) as u16 | ((self.bg_hofs[bg] >> 8) & 0x07)) & 0x03FF;
+        } else {
+          self.bg_vofs[bg] = (((data as u16) << 8) | self.bg_ofs_latch as u16) & 0x03FF;
+        }
+        self.bg_ofs_latch = data;
       }
-      0x210E => self.bg1vofs = data,
-      0x210F..=0x2114 => {}, // FIXME BG2,3,4Xスクロール
       0x2115 => self.vmain = data,
-      0x211A..=0x2120 => {}, // FIXME Mode 7関係
+      0x211A => self.m7sel = data,
+      0x211B => {
+        self.m7a = self.write_m7_register(data);
+        self.update_mpy();
+      }
+      0x211C => {
+        self.m7b = self.write_m7_register(data);
+        self.update_mpy();
+      }
+      0x211D => self.m7c = self.write_m7_register(data),
+      0x211E => self.m7d = self.write_m7_register(data),
+      0x211F => self.m7x = self.write_m7_register(data) & 0x1FFF,
+      0x2120 => self.m7y = self.write_m7_register(data) & 0x1FFF,
       0x2121 => {
         self.cgadd = data;
         self.cg_write_low = true;
@@ -430,20 +1220,42 @@ impl PPU {
         }
         self.cg_write_low = !self.cg_write_low;
       },
-      0x2123..=0x0212B => {},
+      0x2123 => self.w12sel = data,
+      0x2124 => self.w34sel = data,
+      0x2125 => self.wobjsel = data,
+      0x2126 => self.wh0 = data,
+      0x2127 => self.wh1 = data,
+      0x2128 => self.wh2 = data,
+      0x2129 => self.wh3 = data,
+      0x212A => self.wbglog = data,
+      0x212B => self.wobjlog = data,
       0x212C => self.tm = data,
       0x212D => self.ts = data,
       0x212E => self.tmw = data,
-      0x212F => {} // FIXME Window Area Sub Screen Disable
+      0x212F => self.tsw = data,
       0x2130 => self.cgwsel = data,
       0x2131 => self.cgadsub = data,
-      0x2132 => {} // FIXME Color Math Sub Screen Backdrop Color
+      0x2132 => {
+        // bit7-5: B/G/R のどれに書き込むか、bit4-0: 値
+        let value = (data & 0x1F) as u16;
+        if data & 0x20 != 0 {
+          self.coldata = (self.coldata & !0x001F) | value;
+        }
+        if data & 0x40 != 0 {
+          self.coldata = (self.coldata & !0x03E0) | (value << 5);
+        }
+        if data & 0x80 != 0 {
+          self.coldata = (self.coldata & !0x7C00) | (value << 10);
+        }
+      }
       0x2133 => self.setini = data,
       0x2116 => {
         self.vmadd = self.replace_lsb(self.vmadd, data);
+        self.prefetch_vram();
       }
       0x2117 => {
         self.vmadd = self.replace_msb(self.vmadd, data);
+        self.prefetch_vram();
       }
       0x2118 => self.write_vmdatal(data),
       0x2119 => self.write_vmdatah(data),
@@ -454,50 +1266,98 @@ impl PPU {
         }
       }
       0x4201 => self.wrio = data,
-      _ => panic!("not implement PPU::write({:04X}, {:02X})", addr, data),
+      0x4207 => self.htimel = data,
+      0x4208 => self.htimeh = data & 0x01,
+      0x4209 => self.vtimel = data,
+      0x420A => self.vtimeh = data & 0x01,
+      _ => return None,
     }
+    Some(())
   }
 
-  pub fn read(&mut self, addr: u16) -> u8 {
-    match addr {
+  // mdr: CPU のオープンバス (Bus の MDR)
+  // 未接続のアドレスなら None
+  pub fn read(&mut self, addr: u16, mdr: u8) -> Option<u8> {
+    let value = match addr {
+      // 書き込み専用レジスタ。一部は PPU1 のオープンバス、それ以外は CPU のオープンバスが見える
+      0x2104..=0x2106 | 0x2108..=0x210A | 0x2114..=0x2116 | 0x2118..=0x211A | 0x2124..=0x2126 | 0x2128..=0x212A => {
+        self.ppu1_mdr
+      }
+      0x2100..=0x2133 => mdr,
+      0x2134 => {
+        self.ppu1_mdr = self.mpy as u8;
+        self.ppu1_mdr
+      }
+      0x2135 => {
+        self.ppu1_mdr = (self.mpy >> 8) as u8;
+        self.ppu1_mdr
+      }
+      0x2136 => {
+        self.ppu1_mdr = (self.mpy >> 16) as u8;
+        self.ppu1_mdr
+      }
+      0x2138 => { // 2138h RO - RDOAM   - OAMデータ読み込み
+        self.ppu1_mdr = self.read_oamdata();
+        self.ppu1_mdr
+      }
+      0x2139 => { // 2139h RO - RDVRAML - VRAMデータ読み込み (下位8bit)
+        self.ppu1_mdr = self.read_vmdatal();
+        self.ppu1_mdr
+      }
+      0x213A => { // 213Ah RO - RDVRAMH - VRAMデータ読み込み (上位8bit)
+        self.ppu1_mdr = self.read_vmdatah();
+        self.ppu1_mdr
+      }
+      0x213B => { // 213Bh RO - RDCGRAM - パレットデータ読み込み
+        self.ppu2_mdr = self.read_cgdata();
+        self.ppu2_mdr
+      }
       0x2137 => { // 2137h RO - SLHV    - H/Vカウンタラッチ
         if self.wrio & 0x80 != 0 {
           self.ophct = self.h_counter;
           self.opvct = self.v_counter;
         }
-        0 // オープンバス
+        mdr // オープンバス
       }
+      // 上位バイトの bit1-7 は PPU2 のオープンバス
       0x213C => {
         let val = if self.ophct_low {
-          self.ophct & 0x00FF
+          (self.ophct & 0x00FF) as u8
         } else {
-          (self.ophct & 0xFF00) >> 8
+          (((self.ophct & 0xFF00) >> 8) as u8 & 0x01) | (self.ppu2_mdr & 0xFE)
         };
         self.ophct_low = !self.ophct_low;
-        val as u8
+        self.ppu2_mdr = val;
+        val
       }
       0x213D => {
         let val = if self.opvct_low {
-          self.opvct & 0x00FF
+          (self.opvct & 0x00FF) as u8
         } else {
-          (self.opvct & 0xFF00) >> 8
+          (((self.opvct & 0xFF00) >> 8) as u8 & 0x01) | (self.ppu2_mdr & 0xFE)
         };
         self.opvct_low = !self.opvct_low;
-        val as u8
+        self.ppu2_mdr = val;
+        val
       }
+      // bit4 は PPU1 のオープンバス
       0x213E => {
-        self.stat77
+        self.ppu1_mdr = (self.stat77 & 0xEF) | (self.ppu1_mdr & 0x10);
+        self.ppu1_mdr
       }
+      // bit5 は PPU2 のオープンバス
       0x213F => {
-        self.stat78
+        self.ppu2_mdr = (self.stat78 & 0xDF) | (self.ppu2_mdr & 0x20);
+        self.ppu2_mdr
       }
+      // 4210h bit4-6、4211h bit0-6、4212h bit1-5 は CPU のオープンバス
       0x4210 => {
-        let res = self.rdnmi;
+        let res = (self.rdnmi & 0x8F) | (mdr & 0x70);
         self.clear_nmi();
         res
       }
       0x4211 => {
-        let v = self.timeup;
+        let v = (self.timeup & 0x80) | (mdr & 0x7F);
         self.timeup = self.timeup & 0x7F;
         v
       }
@@ -505,9 +1365,73 @@ impl PPU {
         (if self.vblank_flag { 0x80 } else { 0x00 })
         | (if self.hblank_flag { 0x40 } else { 0x00 })
         | (if self.auto_joypad_flag { 0x01 } else { 0x00 })
+        | (mdr & 0x3E)
       }
-      _ => panic!("not implement PPU::read({:04X})", addr),
+      _ => return None,
+    };
+    Some(value)
+  }
+
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // フレームの途中でモザイクを有効にしても、その時点からブロックが始まる
+  #[test]
+  fn mosaic_enabled_mid_frame() {
+    let mut ppu = PPU::new();
+    for scanline in 1..=10 {
+      ppu.mosaic_scanline(scanline);
+      assert_eq!(ppu.mosaic_line(0, scanline), scanline);
+    }
+    // サイズ 4、BG1 のみ
+    ppu.mosaic = 0x31;
+    for scanline in 11..=18 {
+      ppu.mosaic_scanline(scanline);
+      let start = if scanline < 15 { 11 } else { 15 };
+      assert_eq!(ppu.mosaic_line(0, scanline), start);
+      assert_eq!(ppu.mosaic_line(1, scanline), scanline);
     }
   }
 
+  // Mode 2/4 のオフセットパータイルは BG3 のタイルマップから 2 列目以降のスクロール位置を読む
+  #[test]
+  fn offset_per_tile() {
+    let mut ppu = PPU::new();
+    ppu.bgmode = 2;
+    // BG3 のタイルマップは 0000h。1 行目が H、2 行目が V (BG1 のみ)
+    ppu.vmdata[0] = 0x2000 | 0x0043;
+    ppu.vmdata[32] = 0x2000 | 0x0010;
+    assert_eq!(ppu.offset_per_tile(0, 2, 2, 5, 3), (5, 3));
+    assert_eq!(ppu.offset_per_tile(0, 2, 8, 5, 3), (0x45, 0x10));
+    assert_eq!(ppu.offset_per_tile(1, 2, 8, 5, 3), (5, 3));
+
+    // Mode 4 は bit15 で H/V を切り替える
+    ppu.bgmode = 4;
+    ppu.vmdata[0] = 0x8000 | 0x4000 | 0x0020;
+    assert_eq!(ppu.offset_per_tile(0, 4, 8, 5, 3), (5, 3));
+    assert_eq!(ppu.offset_per_tile(1, 4, 8, 5, 3), (5, 0x20));
+  }
+
+  // RDNMI を読んでも V-Blank の開始は繰り返されず、HVBJOY の V-Blank フラグも落ちない
+  #[test]
+  fn rdnmi_read_keeps_vblank() {
+    let mut ppu = PPU::new();
+    while !ppu.frame_updated {
+      ppu.tick(4);
+    }
+    ppu.frame_updated = false;
+    for _ in 0..100 {
+      ppu.tick(4);
+    }
+    assert_eq!(ppu.read(0x4210, 0x00).unwrap() & 0x80, 0x80);
+    assert_eq!(ppu.read(0x4210, 0x00).unwrap() & 0x80, 0x00);
+    for _ in 0..100 {
+      ppu.tick(4);
+    }
+    assert!(!ppu.frame_updated);
+    assert_eq!(ppu.read(0x4212, 0x00).unwrap() & 0x80, 0x80);
+  }
 }